@@ -125,6 +125,23 @@ const Escrow = () => {
     }
   }
 
+  // Derives the program config and collection PDAs, and for SPL-tokens the mint policy and rewards pool (if the mint has one)
+  const getProgramPDAs = async(program, tokenMint) => {
+    const [configPDA] = await PublicKey.findProgramAddress([anchor.utils.bytes.utf8.encode("config")], program.programId);
+    let [collectionPDA] = await PublicKey.findProgramAddress([anchor.utils.bytes.utf8.encode("collection")], program.programId);
+    // Keys are minted without a collection until the admin creates it
+    if (!(await program.account.keyCollection.fetchNullable(collectionPDA))) {
+      collectionPDA = null;
+    }
+    if (!tokenMint) {
+      return {configPDA, collectionPDA, mintPolicyPDA: null, rewardsPoolPDA: null, rewardsPool: null};
+    }
+    const [mintPolicyPDA] = await PublicKey.findProgramAddress([anchor.utils.bytes.utf8.encode("mint_policy"), tokenMint.toBuffer()], program.programId);
+    const [rewardsPoolPDA] = await PublicKey.findProgramAddress([anchor.utils.bytes.utf8.encode("rewards_pool"), tokenMint.toBuffer()], program.programId);
+    const rewardsPool = await program.account.rewardsPool.fetchNullable(rewardsPoolPDA);
+    return {configPDA, collectionPDA, mintPolicyPDA, rewardsPoolPDA: rewardsPool ? rewardsPoolPDA : null, rewardsPool};
+  }
+
   const createToken = async () => {
     const provider = await getProvider();
     setMessage("Creating a new token.");
@@ -297,10 +314,13 @@ const Escrow = () => {
       ];
       const [counterPDA] = await PublicKey.findProgramAddress(seedsCounter, program.programId);
       //console.log("counterPDA (in initialization)=",counterPDA.toString());
+      const {configPDA, mintPolicyPDA} = await getProgramPDAs(program, mint);
 
       const tx = await program.methods.initializeTokenEscrow(tokenAmount).accounts({
         user: provider.wallet.publicKey,
+        config: configPDA,
         tokenMint: mint,
+        mintPolicy: mintPolicyPDA,
         userTokenAta: ata, // user token account == ata
         nftMint: nftMintKP.publicKey,
         escrow: escrowPDA,
@@ -354,8 +374,10 @@ const Escrow = () => {
       ];
       const [counterPDA] = await PublicKey.findProgramAddress(seedsCounter, program.programId);
       //console.log("counterPDA (in initialization)=",counterPDA.toString());
+      const {configPDA} = await getProgramPDAs(program, null);
       const tx = await program.methods.initializeSolEscrow(tokenAmount).accounts({
         user: provider.wallet.publicKey,
+        config: configPDA,
         nftMint: nftMintKP.publicKey,
         escrow: escrowPDA,
         userEscrowCounter: counterPDA,
@@ -387,21 +409,27 @@ const Escrow = () => {
       const [escrowPDA] = await PublicKey.findProgramAddress(seeds, program.programId)
       //console.log("escrowPDA (in retrievation)="+escrowPDA.toString())
       // Get ATA for NFT 
-      let nft_mint = (await program.account.escrow.fetch(escrowPDA)).nftMint;
+      const escrow = await program.account.escrow.fetch(escrowPDA);
+      let nft_mint = escrow.nftMint;
       let nft_ata = await splToken.getAssociatedTokenAddress(nft_mint, provider.wallet.publicKey); 
       // Derive counter and metadata PDAs from seeds
       const seedsCounter = [anchor.utils.bytes.utf8.encode("counter"), provider.wallet.publicKey.toBuffer(),];
       const [counterPDA] = await PublicKey.findProgramAddress(seedsCounter, program.programId);
       const seedsMetadata =[anchor.utils.bytes.utf8.encode("metadata"), nft_mint.toBuffer()];
       const [metadataPDA] = await PublicKey.findProgramAddress(seedsMetadata, program.programId);
+      const {configPDA, collectionPDA, mintPolicyPDA, rewardsPoolPDA} = await getProgramPDAs(program, escrow.isSolana ? null : escrow.tokenMint);
 
       const tx = await program.methods.getNft().accounts({
           user: provider.wallet.publicKey,
+          config: configPDA,
+          collection: collectionPDA,
           nftMint: nft_mint,
           metadataAccount: metadataPDA,
           escrow: escrowPDA,
           userEscrowCounter: counterPDA,
           userNftAta: nft_ata,
+          mintPolicy: mintPolicyPDA,
+          rewardsPool: rewardsPoolPDA,
           systemProgram: SystemProgram.programId.toString(),
           tokenProgram: splToken.TOKEN_PROGRAM_ID,
           associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
@@ -444,27 +472,43 @@ const Escrow = () => {
         let token_mint = (await program.account.escrow.fetch(escrowPDA)).tokenMint;
         // Get user's NFT token account 
         let userTokenAta = await splToken.getAssociatedTokenAddress(token_mint, provider.wallet.publicKey); 
+        const {configPDA, mintPolicyPDA, rewardsPoolPDA, rewardsPool} = await getProgramPDAs(program, token_mint);
+        // The accrued rewards are paid to the holder's token account of the reward mint
+        let holderRewardAta = rewardsPool ? await splToken.getAssociatedTokenAddress(rewardsPool.rewardMint, provider.wallet.publicKey) : null;
 
         tx = await program.methods.retrieve().accounts({
           user: provider.wallet.publicKey,
+          holder: provider.wallet.publicKey,
+          config: configPDA,
           nftMint: nftMint,
           metadataAccount: metadataPDA,
           escrow: escrowPDA,
           userNftAta: nftAta,
           escrowTokenAta: escrowTokenAta,
           userTokenAta: userTokenAta,
+          mintPolicy: mintPolicyPDA,
+          rewardsPool: rewardsPoolPDA,
+          rewardVault: rewardsPool ? rewardsPool.rewardVault : null,
+          holderRewardAta: holderRewardAta,
         }).rpc({
           skipPreflight:true
         })
       } else {
+        const {configPDA} = await getProgramPDAs(program, null);
         tx = await program.methods.retrieve().accounts({
           user: provider.wallet.publicKey,
+          holder: provider.wallet.publicKey,
+          config: configPDA,
           nftMint: nftMint,
           metadataAccount: metadataPDA,
           escrow: escrowPDA,
           userNftAta: nftAta,
           escrowTokenAta: nftAta,
           userTokenAta: nftAta,
+          mintPolicy: null,
+          rewardsPool: null,
+          rewardVault: null,
+          holderRewardAta: null,
         }).rpc({
           skipPreflight:true
         })
@@ -11,15 +11,81 @@
     `initialize_token_escrow`: Deposits SPL-tokens into the token account managed by the escrow PDA.
     `inizialize_sol_escrow`: Deposits Solana native coins ($SOL) into the escrow PDA.
     `get_nft`: Mints an NFT to the user's wallet. This NFT is required for later asset retrieval.
+    `get_nft_for`: Same as `get_nft`, but mints the NFT straight into a recipient's wallet (e.g. for payroll).
     `retrieve`: Burns the provided NFT and transfers the deposited coins or SPL-tokens back to the user. It also closes accounts that are no longer needed.
+                An approved SPL delegate of the NFT can retrieve too, the assets then go to the owner of the NFT's token account.
+    `migrate_escrow`: Permissionless, grows an escrow created by an earlier version of the program to the current layout (the caller
+                      pays the extra rent), so it can be retrieved again.
+    `claim_rewards`: Pays the NFT holder the rewards accrued by the escrow so far (paid out on `retrieve` too, and to the depositor
+                     or beneficiary when the escrow is closed otherwise).
+    `make_vesting`: Turns a fresh escrow (before its NFT is minted) into a linear vesting schedule with a cliff.
+    `claim_vested`: Lets the NFT holder withdraw the part of a vesting escrow that has vested so far.
+    `get_voting_power`: Returns (via return data) the governance weight of a vesting escrow's NFT holder,
+                        the escrowed amount times the remaining lock time, so it decays linearly until `end_ts`.
+    `get_escrow_info`: Returns (via return data) an escrow's status, asset, amount, NFT mint and holder.
+    `get_user_escrows_summary`: Returns (via return data) totals over the open escrows of a user (passed as remaining accounts).
+    `set_arbiter`: Designates a neutral arbiter (before the NFT is minted) whose approval is then required for `retrieve`.
+    `approve_release`: The arbiter confirms delivery, allowing the NFT holder to retrieve the assets.
+    `refund`: The arbiter returns the assets of an escrow with a key to the depositor instead, which makes the NFT worthless.
+    `set_retrieval_signers`: Requires M-of-N additional signatures (passed as remaining signer accounts) for `retrieve` and `claim_vested`.
+    `set_expiry`: Sets the time after which the escrow can no longer be retrieved with its NFT.
+    `expire`: Permissionless, returns the assets of an expired escrow with a key to the depositor and freezes its NFT, voiding it.
+    `make_dead_man_switch`: Names a beneficiary who can claim the escrow if the depositor stops sending heartbeats.
+    `heartbeat`: The depositor proves they are still around, postponing the beneficiary's claim.
+    `claim_inheritance`: The beneficiary claims the assets after a missed heartbeat, without holding the NFT.
+    `lock_with_secret`: Binds a fresh escrow to the public key of a keypair derived from a secret instead of an NFT, e.g. for link-based gifts.
+    `claim_with_secret`: Whoever proves knowledge of the secret by signing their own address with it receives the assets of a hash-locked escrow.
+    `reclaim_secret_escrow`: The depositor takes back an unclaimed hash-locked escrow after its timeout.
+    `redeem_voucher`: Pays out an amount authorized off-chain by a depositor-signed voucher (verified via the Ed25519 program).
+    `start_stream`: Turns a fresh escrow into a payment stream flowing to a recipient at a per-second rate (no NFT).
+    `withdraw_streamed`: The stream recipient withdraws everything accrued so far.
+    `cancel_stream`: The sender stops the stream, the accrued part goes to the recipient and the rest back to the sender.
+    `register_recurring_deposit`: Approves the escrow PDA as a delegate on the depositor's token account and registers a savings plan.
+    `crank_deposit`: Permissionless, pulls the next instalment of a recurring plan into the escrow once its interval has elapsed.
+    `cancel_recurring_deposit`: Stops a recurring plan and revokes the delegation.
+    `split_escrow`: Burns the NFT of a standard escrow and splits the escrow in two, each with a fresh NFT for the holder.
+    `merge_escrows`: Burns the NFTs of two standard escrows of the same asset, moves everything into the first one and issues a single fresh NFT.
+    `rotate_key`: Burns the holder's NFT and issues a fresh one under a new mint, invalidating e.g. a compromised delegation.
+    `make_soulbound`: Makes the NFT of a fresh escrow non-transferable (its token account stays frozen, thawed only to burn it).
+    `list_key`: Puts the holder's NFT up for sale at an asking price in $SOL or an SPL-token, the NFT is held by the listing PDA meanwhile.
+    `update_listing_price` / `cancel_listing`: The seller changes the asking price or takes the NFT back.
+    `buy_listing`: Pays the seller and transfers the listed NFT to the buyer in one instruction.
+    `create_auction`: Puts the holder's NFT up for an English auction with a reserve price and an end time.
+    `place_bid`: Escrows the bid in the auction PDA (or its token account) and refunds the outbid bidder.
+    `settle_auction`: Permissionless after the end, hands the NFT to the highest bidder and the bid to the seller
+                      (or the NFT back to the seller if there were no bids or the key's escrow is no longer retrievable).
+    `create_loan_offer`: A lender deposits a loan (in $SOL or an SPL-token) into an offer PDA, with the repayment, duration
+                         and the escrowed asset and minimum escrowed amount (per the NFT metadata) it accepts as collateral.
+    `cancel_loan_offer`: The lender withdraws an untaken offer.
+    `borrow`: A holder locks their NFT in a loan PDA and receives the offered loan.
+    `repay_loan`: The borrower pays the repayment to the lender and gets the NFT back.
+    `claim_collateral`: After a missed due date, the lender takes the locked NFT.
+    `setup_milestones`: Splits a fresh escrow into milestones paid out to a payee (no NFT is minted for such escrows).
+    `submit_milestone` / `approve_milestone`: The payee reports a milestone as done, the payer releases its amount.
+    `open_dispute` / `resolve_dispute`: Either side can dispute a milestone, the arbiter then splits its amount between them.
+  Administration:
+    `init_config`: Creates the program config account. Only the program's upgrade authority can do this and it becomes the admin.
+    `update_config`: Lets the admin choose whether retrievals of existing keys stay possible while the program is paused.
+    `pause` / `unpause`: Emergency switch that stops new deposits (and optionally retrievals) until the admin unpauses the program.
+    `init_collection`: Creates the collection mint recorded in the `metadata` PDA of every key minted afterwards. This is not a
+                      Metaplex collection, wallets and marketplaces do not display it. Keys can be minted before it exists.
+    `register_mint` / `update_mint_policy`: Allowlists an SPL-token mint with a minimum deposit, a maximum per escrow and a global TVL cap.
+    `init_rewards_pool` / `fund_rewards_pool` / `set_reward_rate`: Emits a reward token to the key holders of an allowlisted mint's escrows,
+                      in proportion to the escrowed amount and the time since the key was minted.
   Compatibility:
     Anchor framework v0.27.0
 */
 
 use anchor_lang::prelude::*;
 use anchor_lang::system_program;
-use anchor_spl::associated_token::AssociatedToken;
+use anchor_lang::Discriminator;
+use anchor_lang::solana_program::ed25519_program;
+use anchor_lang::solana_program::program::set_return_data;
+use anchor_lang::solana_program::program_option::COption;
+use anchor_lang::solana_program::sysvar::instructions::{load_current_index_checked, load_instruction_at_checked};
+use anchor_spl::associated_token::{get_associated_token_address, AssociatedToken};
 use anchor_spl::token::{Mint, Token, TokenAccount};
+use anchor_spl::token::spl_token::instruction::AuthorityType;
 
 declare_id!("8KwgsMuDE7HLLKFF22Hnt9ghJZWskQHbZTCmwwk3vzUi");
 
@@ -30,6 +96,151 @@ pub mod escrow_headon_attempt {
     // The ordering of the following procedures (functions) in this source code is similar
     // to the expected application workflow (counter initialization, escrow deposit, nft reception and at last asset retrieval)
 
+    pub fn init_config(ctx: Context<InitConfig>, allow_retrieve_while_paused: bool) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        config.admin = ctx.accounts.admin.key();
+        config.paused = false;
+        config.allow_retrieve_while_paused = allow_retrieve_while_paused;
+        config.bump = *ctx.bumps.get("config").unwrap();
+        msg!("Program config initialized, admin: {}", config.admin);
+        Ok(())
+    }
+
+    pub fn update_config(ctx: Context<UpdateConfig>, allow_retrieve_while_paused: bool) -> Result<()> {
+        ctx.accounts.config.allow_retrieve_while_paused = allow_retrieve_while_paused;
+        Ok(())
+    }
+
+    pub fn pause(ctx: Context<UpdateConfig>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        if config.paused {
+            return err!(CustomError::AlreadyPaused);
+        }
+        config.paused = true;
+        msg!("The escrow program is now paused.");
+        emit!(ProgramPaused {
+            admin: config.admin,
+            allow_retrieve_while_paused: config.allow_retrieve_while_paused,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        Ok(())
+    }
+
+    pub fn unpause(ctx: Context<UpdateConfig>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        if !config.paused {
+            return err!(CustomError::NotPaused);
+        }
+        config.paused = false;
+        msg!("The escrow program is running again.");
+        emit!(ProgramUnpaused {
+            admin: config.admin,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        Ok(())
+    }
+
+    pub fn init_collection(ctx: Context<InitCollection>) -> Result<()> {
+        let collection = &mut ctx.accounts.collection;
+        collection.collection_mint = ctx.accounts.collection_mint.key();
+        collection.minted = 0;
+        collection.bump = *ctx.bumps.get("collection").unwrap();
+
+        msg!("Minting the collection NFT {}.", collection.collection_mint);
+        anchor_spl::token::mint_to(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                anchor_spl::token::MintTo {
+                    mint: ctx.accounts.collection_mint.to_account_info(),
+                    to: ctx.accounts.collection_ata.to_account_info(),
+                    authority: collection.to_account_info(),
+                },
+                &[&[b"collection", &[collection.bump]]],
+            ), 1)?;
+        Ok(())
+    }
+
+    pub fn register_mint(ctx: Context<RegisterMint>, min_deposit: u64, max_per_escrow: u64, tvl_cap: u64) -> Result<()> {
+        if min_deposit > max_per_escrow {
+            return err!(CustomError::InvalidMintPolicy);
+        }
+        let mint_policy = &mut ctx.accounts.mint_policy;
+        mint_policy.token_mint = ctx.accounts.token_mint.key();
+        mint_policy.enabled = true;
+        mint_policy.min_deposit = min_deposit;
+        mint_policy.max_per_escrow = max_per_escrow;
+        mint_policy.tvl_cap = tvl_cap;
+        mint_policy.total_deposited = 0;
+        mint_policy.bump = *ctx.bumps.get("mint_policy").unwrap();
+        mint_policy.rewards_enabled = false;
+        msg!("Mint {} was added to the allowlist.", mint_policy.token_mint);
+        Ok(())
+    }
+
+    pub fn update_mint_policy(ctx: Context<UpdateMintPolicy>, enabled: bool, min_deposit: u64, max_per_escrow: u64, tvl_cap: u64) -> Result<()> {
+        if min_deposit > max_per_escrow {
+            return err!(CustomError::InvalidMintPolicy);
+        }
+        let mint_policy = &mut ctx.accounts.mint_policy;
+        mint_policy.enabled = enabled;
+        mint_policy.min_deposit = min_deposit;
+        mint_policy.max_per_escrow = max_per_escrow;
+        // Lowering the cap below the current TVL only blocks new deposits, existing escrows stay retrievable
+        mint_policy.tvl_cap = tvl_cap;
+        Ok(())
+    }
+
+    pub fn init_rewards_pool(ctx: Context<InitRewardsPool>, reward_rate: u64) -> Result<()> {
+        let rewards_pool = &mut ctx.accounts.rewards_pool;
+        rewards_pool.token_mint = ctx.accounts.mint_policy.token_mint;
+        rewards_pool.reward_mint = ctx.accounts.reward_mint.key();
+        rewards_pool.reward_vault = ctx.accounts.reward_vault.key();
+        rewards_pool.reward_rate = reward_rate;
+        rewards_pool.acc_reward_per_token = 0;
+        rewards_pool.last_update_ts = Clock::get()?.unix_timestamp;
+        rewards_pool.bump = *ctx.bumps.get("rewards_pool").unwrap();
+        rewards_pool.total_staked = 0;
+        // Existing escrows of the mint join the pool on their next update (e.g. `claim_rewards`)
+        ctx.accounts.mint_policy.rewards_enabled = true;
+        emit!(RewardsPoolCreated {
+            rewards_pool: rewards_pool.key(),
+            token_mint: rewards_pool.token_mint,
+            reward_mint: rewards_pool.reward_mint,
+            reward_rate,
+        });
+        Ok(())
+    }
+
+    pub fn fund_rewards_pool(ctx: Context<FundRewardsPool>, amount: u64) -> Result<()> {
+        msg!("Transfering {} reward tokens to the rewards pool.", amount);
+        anchor_spl::token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                anchor_spl::token::Transfer {
+                    from: ctx.accounts.admin_reward_ata.to_account_info(),
+                    to: ctx.accounts.reward_vault.to_account_info(),
+                    authority: ctx.accounts.admin.to_account_info(),
+                },
+            ), amount)?;
+        emit!(RewardsPoolFunded {
+            rewards_pool: ctx.accounts.rewards_pool.key(),
+            amount,
+        });
+        Ok(())
+    }
+
+    pub fn set_reward_rate(ctx: Context<SetRewardRate>, reward_rate: u64) -> Result<()> {
+        let rewards_pool = &mut ctx.accounts.rewards_pool;
+        // Everything up to now accrues at the old rate
+        rewards_pool.update(Clock::get()?.unix_timestamp)?;
+        rewards_pool.reward_rate = reward_rate;
+        emit!(RewardRateUpdated {
+            rewards_pool: rewards_pool.key(),
+            reward_rate,
+        });
+        Ok(())
+    }
+
     pub fn init_counter(ctx: Context<InitCounter>) -> Result<()> {
         let counter_account = &mut ctx.accounts.user_escrow_counter;
         counter_account.user = ctx.accounts.user.key();
@@ -39,6 +250,63 @@ pub mod escrow_headon_attempt {
         Ok(())
     }
 
+    pub fn migrate_escrow(ctx: Context<MigrateEscrow>, escrow_number: u64) -> Result<()> {
+        let escrow_info = ctx.accounts.escrow.to_account_info();
+        let legacy = {
+            let data = escrow_info.try_borrow_data()?;
+            if data.len() != LegacyEscrow::LEN || data[..8] != Escrow::DISCRIMINATOR {
+                return err!(CustomError::NotLegacyEscrow);
+            }
+            LegacyEscrow::deserialize(&mut &data[8..])?
+        };
+        // Escrows of the earlier version did not store their number, it is verified against the escrow's address instead
+        let escrow_number_bytes = escrow_number.to_le_bytes();
+        let seeds: &[&[u8]] = &[b"escrow", legacy.authority.as_ref(), escrow_number_bytes.as_ref(), &[legacy.bump]];
+        if Pubkey::create_program_address(seeds, ctx.program_id).ok() != Some(escrow_info.key()) {
+            return err!(CustomError::InvalidEscrowNumber);
+        }
+
+        msg!("Growing escrow {} to the current layout.", escrow_info.key());
+        let rent = Rent::get()?;
+        let extra_rent = rent.minimum_balance(Escrow::LEN).saturating_sub(rent.minimum_balance(LegacyEscrow::LEN));
+        system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.payer.to_account_info(),
+                    to: escrow_info.clone(),
+                },
+            ), extra_rent)?;
+        escrow_info.realloc(Escrow::LEN, false)?;
+
+        // The escrow keeps its assets and key, everything added since is left at the defaults of a fresh escrow
+        let escrow = Escrow {
+            authority: legacy.authority,
+            bump: legacy.bump,
+            token_mint: legacy.token_mint,
+            escrow_token_ata: legacy.escrow_token_ata,
+            token_amount: legacy.token_amount,
+            nft_mint: legacy.nft_mint,
+            nft_acquired: legacy.nft_acquired,
+            is_solana: legacy.is_solana,
+            kind: EscrowKind::Standard,
+            escrow_number,
+            arbiter: None,
+            release_approved: false,
+            signers: Vec::new(),
+            threshold: 0,
+            expires_at: None,
+            voucher_nonce: 0,
+            soulbound: false,
+            reward_per_token_paid: 0,
+            reward_stake: 0,
+            rewards_owed: 0,
+        };
+        let mut data = escrow_info.try_borrow_mut_data()?;
+        escrow.try_serialize(&mut &mut data[..])?;
+        Ok(())
+    }
+
     pub fn initialize_token_escrow(ctx: Context<InitializeTokenEscrow>, token_amount: u64) -> Result<()> {
         msg!("Updating user's escrow counter.");
         let user_escrow_counter = & mut ctx.accounts.user_escrow_counter;
@@ -52,6 +320,10 @@ pub mod escrow_headon_attempt {
         let escrow_token_ata = & ctx.accounts.escrow_token_ata;
         let token_mint = & ctx.accounts.token_mint;
         let nft_mint = & ctx.accounts.nft_mint; 
+
+        msg!("Checking the deposit against the mint policy.");
+        ctx.accounts.mint_policy.record_deposit(token_amount, token_amount)?;
+
         msg!("Escrow data account {}", escrow.key());
         // Fetch bump from the seeds of ["escrow"],
         // The Bump is the value the gets us from the ED25519 Eliptic Curve (so the PDA does not have Private Key)
@@ -63,6 +335,19 @@ pub mod escrow_headon_attempt {
         escrow.nft_mint = nft_mint.key(); // Save NFT Mint Address
         escrow.nft_acquired = false;
         escrow.is_solana = false;
+        escrow.kind = EscrowKind::Standard;
+        escrow.escrow_number = user_escrow_counter.previous_counter;
+        escrow.arbiter = None;
+        escrow.release_approved = false;
+        escrow.signers = Vec::new();
+        escrow.threshold = 0;
+        escrow.expires_at = None;
+        escrow.voucher_nonce = 0;
+        escrow.soulbound = false;
+        // The escrow starts accruing rewards once its NFT is minted
+        escrow.reward_per_token_paid = 0;
+        escrow.reward_stake = 0;
+        escrow.rewards_owed = 0;
 
         msg!("Transfering tokens to escrow's token account.");
         anchor_spl::token::transfer(
@@ -99,6 +384,18 @@ pub mod escrow_headon_attempt {
         escrow.nft_mint = nft_mint.key(); // Save NFT Mint Address
         escrow.nft_acquired = false;
         escrow.is_solana = true;
+        escrow.kind = EscrowKind::Standard;
+        escrow.escrow_number = user_escrow_counter.previous_counter;
+        escrow.arbiter = None;
+        escrow.release_approved = false;
+        escrow.signers = Vec::new();
+        escrow.threshold = 0;
+        escrow.expires_at = None;
+        escrow.voucher_nonce = 0;
+        escrow.soulbound = false;
+        escrow.reward_per_token_paid = 0;
+        escrow.reward_stake = 0;
+        escrow.rewards_owed = 0;
 
         msg!("Transfering Solana coin to escrow.");
         system_program::transfer(
@@ -115,200 +412,3412 @@ pub mod escrow_headon_attempt {
     }
 
     pub fn get_nft(ctx: Context<GetNFT>) -> Result<()> {
-        assert!(ctx.accounts.escrow.nft_acquired == false, "NFT already acquired!");
+        ctx.accounts.escrow.check_key_mintable()?;
+        let accounts = ctx.accounts;
+        let recipient = accounts.user.key();
+        mint_key(
+            &mut accounts.escrow,
+            KeyMint {
+                metadata_account: &mut accounts.metadata_account,
+                metadata_bump: *ctx.bumps.get("metadata_account").unwrap(),
+                nft_mint: &accounts.nft_mint,
+                recipient_nft_ata: &accounts.user_nft_ata,
+            },
+            accounts.collection.as_mut(),
+            recipient,
+            &accounts.user,
+            &accounts.token_program,
+        )?;
+        sync_rewards(&mut accounts.escrow, accounts.mint_policy.as_ref(), accounts.rewards_pool.as_mut(), Clock::get()?.unix_timestamp, false)?;
+        Ok(())
+    }
+
+    pub fn get_nft_for(ctx: Context<GetNFTFor>) -> Result<()> {
+        ctx.accounts.escrow.check_key_mintable()?;
+        let accounts = ctx.accounts;
+        mint_key(
+            &mut accounts.escrow,
+            KeyMint {
+                metadata_account: &mut accounts.metadata_account,
+                metadata_bump: *ctx.bumps.get("metadata_account").unwrap(),
+                nft_mint: &accounts.nft_mint,
+                recipient_nft_ata: &accounts.recipient_nft_ata,
+            },
+            accounts.collection.as_mut(),
+            accounts.recipient.key(),
+            &accounts.user,
+            &accounts.token_program,
+        )?;
+        sync_rewards(&mut accounts.escrow, accounts.mint_policy.as_ref(), accounts.rewards_pool.as_mut(), Clock::get()?.unix_timestamp, false)?;
+        Ok(())
+    }
+
+    pub fn claim_rewards(ctx: Context<ClaimRewards>) -> Result<()> {
+        let accounts = ctx.accounts;
+        let escrow = &mut accounts.escrow;
+        let rewards_pool = &mut accounts.rewards_pool;
+        sync_rewards(escrow, Some(&accounts.mint_policy), Some(rewards_pool), Clock::get()?.unix_timestamp, false)?;
+        let rewards = escrow.rewards_owed;
+        if rewards > accounts.reward_vault.amount {
+            return err!(CustomError::RewardsPoolUnderfunded);
+        }
+        escrow.rewards_owed = 0;
+
+        msg!("Paying out {} reward tokens.", rewards);
+        pay_rewards(rewards_pool, rewards, &accounts.reward_vault, &accounts.user_reward_ata, accounts.user.key(), &accounts.token_program)?;
+        emit!(RewardsClaimed {
+            escrow: escrow.key(),
+            holder: accounts.user.key(),
+            amount: rewards,
+        });
+        Ok(())
+    }
 
-        // Get account references (improves readability later)
+    pub fn make_vesting(ctx: Context<ConfigureEscrow>, start_ts: i64, cliff_ts: i64, end_ts: i64) -> Result<()> {
+        if !(start_ts <= cliff_ts && cliff_ts <= end_ts && start_ts < end_ts) {
+            return err!(CustomError::InvalidVestingSchedule);
+        }
         let escrow = &mut ctx.accounts.escrow;
-        let user_escrow_counter = &ctx.accounts.user_escrow_counter;
-        let metadata_account = &mut ctx.accounts.metadata_account;
-        let nft_mint = & ctx.accounts.nft_mint;
+        if escrow.kind != EscrowKind::Standard {
+            return err!(CustomError::EscrowKindAlreadySet);
+        }
+        escrow.kind = EscrowKind::Vesting { start_ts, cliff_ts, end_ts, released_amount: 0 };
+        msg!("Escrow {} now vests from {} (cliff {}) to {}.", escrow.key(), start_ts, cliff_ts, end_ts);
+        Ok(())
+    }
 
-        msg!("Initializing NFT metadata account.");
-        metadata_account.bump = *ctx.bumps.get("metadata_account").unwrap();
-        metadata_account.token_title = String::from("Deposit Box Key");
-        metadata_account.escrow_number = user_escrow_counter.previous_counter;
-        metadata_account.escrowed_token_mint = escrow.token_mint;
-        metadata_account.escrowed_amount = escrow.token_amount;
+    // Read-only, meant to be simulated or called by a governance program through CPI
+    pub fn get_voting_power(ctx: Context<GetVotingPower>) -> Result<()> {
+        let escrow = & ctx.accounts.escrow;
+        let lock_end_ts = match escrow.kind {
+            EscrowKind::Vesting { end_ts, .. } => end_ts,
+            _ => 0,
+        };
+        let voting_power = VotingPower {
+            escrow: escrow.key(),
+            holder: ctx.accounts.holder_nft_ata.owner,
+            voting_power: escrow.voting_power(Clock::get()?.unix_timestamp),
+            lock_end_ts,
+        };
+        msg!("Voting power of {}: {}", voting_power.holder, voting_power.voting_power);
+        set_return_data(&voting_power.try_to_vec()?);
+        Ok(())
+    }
 
-        msg!("NFT mint: {}", nft_mint.key());
-        msg!("NFT metadata account address: {}", metadata_account.key());
+    // Read-only, lets other programs and simulated transactions read an escrow without decoding its raw layout
+    pub fn get_escrow_info(ctx: Context<GetEscrowInfo>) -> Result<()> {
+        let escrow = & ctx.accounts.escrow;
+        let holder = match ctx.accounts.holder_nft_ata.as_ref() {
+            Some(holder_nft_ata) => {
+                if !escrow.nft_acquired || holder_nft_ata.mint != escrow.nft_mint || holder_nft_ata.amount != 1 {
+                    return err!(CustomError::InvalidNftHolder);
+                }
+                Some(holder_nft_ata.owner)
+            }
+            None => None,
+        };
+        let escrow_info = EscrowInfo {
+            escrow: escrow.key(),
+            authority: escrow.authority,
+            escrow_number: escrow.escrow_number,
+            status: escrow.status(Clock::get()?.unix_timestamp),
+            kind: escrow.kind.clone(),
+            token_mint: if escrow.is_solana { None } else { Some(escrow.token_mint) },
+            token_amount: escrow.token_amount,
+            nft_mint: if escrow.nft_acquired { Some(escrow.nft_mint) } else { None },
+            holder,
+            expires_at: escrow.expires_at,
+            soulbound: escrow.soulbound,
+        };
+        set_return_data(&escrow_info.try_to_vec()?);
+        Ok(())
+    }
 
-        msg!("Minting a NFT to user's associated token account...");
-        let user_nft_ata = & ctx.accounts.user_nft_ata;
-        let user = & ctx.accounts.user;
-        anchor_spl::token::mint_to(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                anchor_spl::token::MintTo {
-                    mint: nft_mint.to_account_info(),
-                    to: user_nft_ata.to_account_info(),
-                    authority: user.to_account_info(),
-                },
-            ), 1)?;
-        msg!("Minting was successful.");
-        msg!("Checkmarking NFT issuance.");
-        escrow.nft_acquired = true;
+    // Read-only, the user's escrows are passed as remaining accounts in ascending order of their escrow number
+    // (closed escrows can be passed too, they are skipped)
+    pub fn get_user_escrows_summary<'info>(ctx: Context<'_, '_, '_, 'info, GetUserEscrowsSummary<'info>>) -> Result<()> {
+        let user = ctx.accounts.user.key();
+        let mut summary = UserEscrowsSummary {
+            user,
+            escrows_created: ctx.accounts.user_escrow_counter.counter,
+            open_escrows: 0,
+            keys_issued: 0,
+            sol_escrows: 0,
+            lamports_escrowed: 0,
+            token_escrows: 0,
+        };
+        let mut next_escrow_number = 0;
+        for account in ctx.remaining_accounts.iter() {
+            let escrow = match Account::<Escrow>::try_from(account) {
+                Ok(escrow) => escrow,
+                Err(_) => continue,
+            };
+            // The ordering guarantees that no escrow is counted twice
+            if escrow.authority != user || escrow.escrow_number < next_escrow_number {
+                return err!(CustomError::InvalidEscrowList);
+            }
+            next_escrow_number = escrow.escrow_number + 1;
+            summary.open_escrows += 1;
+            if escrow.nft_acquired {
+                summary.keys_issued += 1;
+            }
+            if escrow.is_solana {
+                summary.sol_escrows += 1;
+                summary.lamports_escrowed = summary.lamports_escrowed.checked_add(escrow.token_amount).ok_or(CustomError::MathOverflow)?;
+            } else {
+                summary.token_escrows += 1;
+            }
+        }
+        set_return_data(&summary.try_to_vec()?);
         Ok(())
     }
 
-    pub fn retrieve(ctx: Context<Retrieve>) -> Result<()> {
-        let user = & ctx.accounts.user;
+    pub fn set_arbiter(ctx: Context<ConfigureEscrow>, arbiter: Pubkey) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.arbiter = Some(arbiter);
+        escrow.release_approved = false;
+        msg!("Retrieval from escrow {} now requires approval of {}.", escrow.key(), arbiter);
+        Ok(())
+    }
+
+    pub fn approve_release(ctx: Context<ApproveRelease>) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.release_approved = true;
+        emit!(ReleaseApproved {
+            escrow: escrow.key(),
+            arbiter: ctx.accounts.arbiter.key(),
+        });
+        Ok(())
+    }
+
+    pub fn refund(ctx: Context<Refund>) -> Result<()> {
+        // Escrows without a key settle through their own rules, milestone disputes go through `resolve_dispute`
+        if !ctx.accounts.escrow.kind.has_key() {
+            return err!(CustomError::UnsupportedEscrowKind);
+        }
+        sync_rewards(&mut ctx.accounts.escrow, ctx.accounts.mint_policy.as_ref(), ctx.accounts.rewards_pool.as_mut(), Clock::get()?.unix_timestamp, true)?;
         let escrow = & ctx.accounts.escrow;
-        let metadata_account = & ctx.accounts.metadata_account;
-        let user_nft_ata = & ctx.accounts.user_nft_ata;
-        let nft_mint = & ctx.accounts.nft_mint;
-        let escrow_token_ata = & ctx.accounts.escrow_token_ata;
-        let user_token_ata = & ctx.accounts.user_token_ata;
-        msg!("Burning the NFT.");
-        anchor_spl::token::burn(
-            CpiContext::new(ctx.accounts.token_program.to_account_info(),
-            anchor_spl::token::Burn {
-                mint: nft_mint.to_account_info(),
-                from: user_nft_ata.to_account_info(),
-                authority: user.to_account_info(), 
-            },),
-            1, // one NFT
-        )?;
-        msg!("Burn successful.");
+        let authority = & ctx.accounts.authority;
+        let escrow_number = escrow.escrow_number.to_le_bytes();
+        let escrow_seeds: &[&[u8]] = &[b"escrow", escrow.authority.as_ref(), escrow_number.as_ref(), &[escrow.bump]];
+
+        if escrow.nft_acquired {
+            msg!("Freezing the NFT, it no longer represents any assets.");
+            void_nft(escrow, ctx.accounts.nft_mint.as_ref(), ctx.accounts.holder_nft_ata.as_ref(), &ctx.accounts.token_program)?;
+        }
+
         if escrow.is_solana {
-            msg!("Transfering Solana to the user.");
-            msg!("Transfer will be handled by escrow account closure.");
+            msg!("Refund of $SOL will be handled by escrow account closure.");
         } else {
-            msg!("Transfering escrowed tokens back to the user.");
-            anchor_spl::token::transfer(
-                CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(),
-                anchor_spl::token::Transfer {
-                    from: escrow_token_ata.to_account_info(),
-                    to: user_token_ata.to_account_info(),
-                    authority: escrow.to_account_info(),
-                },
-                &[&["escrow".as_bytes(), user.key().as_ref(), metadata_account.escrow_number.to_le_bytes().as_ref(), &[escrow.bump]]],
-                ),
-                escrow.token_amount
+            msg!("Refunding escrowed tokens to the depositor.");
+            release_from_escrow(
+                escrow,
+                escrow_seeds,
+                escrow.token_amount,
+                &authority.to_account_info(),
+                ctx.accounts.escrow_token_ata.as_ref(),
+                ctx.accounts.authority_token_ata.as_ref(),
+                &ctx.accounts.token_program,
             )?;
-            msg!("Transfer was successful.");
+            ctx.accounts.mint_policy.as_mut().ok_or(CustomError::MissingMintPolicy)?.record_withdrawal(escrow.token_amount);
+            close_escrow_token_ata(escrow, escrow_seeds, ctx.accounts.escrow_token_ata.as_ref().unwrap(), &authority.to_account_info(), &ctx.accounts.token_program)?;
+        }
+        pay_owed_rewards(escrow, ctx.accounts.rewards_pool.as_ref(), ctx.accounts.reward_vault.as_deref(), ctx.accounts.authority_reward_ata.as_deref(), authority.key(), &ctx.accounts.token_program)?;
+        emit!(EscrowRefunded {
+            escrow: escrow.key(),
+            arbiter: ctx.accounts.arbiter.key(),
+            authority: authority.key(),
+            amount: escrow.token_amount,
+        });
+        Ok(())
+    }
 
-            msg!("Closing the escrow token account (ata).");
-            anchor_spl::token::close_account(
-                CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(),
-                anchor_spl::token::CloseAccount {
-                    account: escrow_token_ata.to_account_info(),
-                    destination: user.to_account_info(),
-                    authority: escrow.to_account_info(),
-                },
-                &[&["escrow".as_bytes(), user.key().as_ref(), metadata_account.escrow_number.to_le_bytes().as_ref(), &[escrow.bump]]],
-            ))?;
-            msg!("Escrow token account (ata) was successfuly closed.");
+    pub fn set_retrieval_signers(ctx: Context<ConfigureEscrow>, signers: Vec<Pubkey>, threshold: u8) -> Result<()> {
+        Escrow::check_signer_set(&signers, threshold)?;
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.signers = signers;
+        escrow.threshold = threshold;
+        msg!("Retrieval from escrow {} now requires {} of {} signatures.", escrow.key(), threshold, escrow.signers.len());
+        Ok(())
+    }
+
+    pub fn set_expiry(ctx: Context<ConfigureEscrow>, expires_at: i64) -> Result<()> {
+        if expires_at <= Clock::get()?.unix_timestamp {
+            return err!(CustomError::InvalidExpiry);
         }
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.expires_at = Some(expires_at);
+        msg!("Escrow {} expires at {}.", escrow.key(), expires_at);
         Ok(())
     }
-}
 
-// Context (de)serialization structures
+    pub fn expire(ctx: Context<Expire>) -> Result<()> {
+        // Escrows without a key settle through their own rules (milestones, streams, hash locks)
+        if !ctx.accounts.escrow.kind.has_key() {
+            return err!(CustomError::UnsupportedEscrowKind);
+        }
+        sync_rewards(&mut ctx.accounts.escrow, ctx.accounts.mint_policy.as_ref(), ctx.accounts.rewards_pool.as_mut(), Clock::get()?.unix_timestamp, true)?;
+        let escrow = & ctx.accounts.escrow;
+        let authority = & ctx.accounts.authority;
+        if !escrow.is_expired(Clock::get()?.unix_timestamp) {
+            return err!(CustomError::EscrowNotExpired);
+        }
+        let escrow_number = escrow.escrow_number.to_le_bytes();
+        let escrow_seeds: &[&[u8]] = &[b"escrow", escrow.authority.as_ref(), escrow_number.as_ref(), &[escrow.bump]];
 
-#[derive(Accounts)]
-pub struct InitCounter<'info> {
-    #[account(mut)]
-    user: Signer<'info>,
-    // PDA (=Program Derived Address) of seeds ["counter", user.PK]
-    #[account(init, payer = user, space = UserEscrowCounter::LEN, seeds = [b"counter", user.key().as_ref()], bump)]
-    pub user_escrow_counter: Account<'info, UserEscrowCounter>,
-    system_program: Program<'info, System>,
-}
+        if escrow.nft_acquired {
+            msg!("Freezing the NFT, it no longer represents any assets.");
+            void_nft(escrow, ctx.accounts.nft_mint.as_ref(), ctx.accounts.holder_nft_ata.as_ref(), &ctx.accounts.token_program)?;
+        }
 
-#[derive(Accounts)]
-pub struct InitializeTokenEscrow<'info> {
-    #[account(mut)]
-    user: Signer<'info>,
-    token_mint: Account<'info, Mint>,
-    // Mutable reference to the user's ATA (=Assosiated Token Account) (that already existed)
-    #[account(mut, constraint = user_token_ata.mint == token_mint.key() && user_token_ata.owner == user.key() || return err!(CustomError::InvalidUserToken))]
-    user_token_ata: Account<'info, TokenAccount>,
-    // Init NFT Mint account
-    #[account(init, payer = user, mint::decimals = 0, mint::authority = user, mint::freeze_authority = user)]
-    nft_mint: Account<'info, Mint>,
-    // Init Escrow Data account
-    #[account(init, payer = user, space = Escrow::LEN, seeds = ["escrow".as_bytes(), user.key().as_ref(), user_escrow_counter.counter.to_le_bytes().as_ref()], bump,)]
-    pub escrow: Account<'info, Escrow>,
-    // Mutable reference to the user's escrow counter
-    #[account(mut, seeds = [b"counter", user.key().as_ref()], bump = user_escrow_counter.bump)]
-    pub user_escrow_counter: Account<'info, UserEscrowCounter>,
-    // Newly created token account for storing the tokens, the authority is the escrow PDA
-    #[account(init, payer = user, token::mint = token_mint, token::authority = escrow,)]
-    escrow_token_ata: Account<'info, TokenAccount>,
-    // Additional Program/Sysvar that are needed
-    token_program: Program<'info, Token>,
-    rent: Sysvar<'info, Rent>,
-    system_program: Program<'info, System>,
-}
+        if escrow.is_solana {
+            msg!("Return of $SOL will be handled by escrow account closure.");
+        } else {
+            msg!("Returning escrowed tokens to the depositor.");
+            release_from_escrow(
+                escrow,
+                escrow_seeds,
+                escrow.token_amount,
+                &authority.to_account_info(),
+                ctx.accounts.escrow_token_ata.as_ref(),
+                ctx.accounts.authority_token_ata.as_ref(),
+                &ctx.accounts.token_program,
+            )?;
+            ctx.accounts.mint_policy.as_mut().ok_or(CustomError::MissingMintPolicy)?.record_withdrawal(escrow.token_amount);
+            close_escrow_token_ata(escrow, escrow_seeds, ctx.accounts.escrow_token_ata.as_ref().unwrap(), &authority.to_account_info(), &ctx.accounts.token_program)?;
+        }
+        pay_owed_rewards(escrow, ctx.accounts.rewards_pool.as_ref(), ctx.accounts.reward_vault.as_deref(), ctx.accounts.authority_reward_ata.as_deref(), authority.key(), &ctx.accounts.token_program)?;
+        emit!(EscrowExpired {
+            escrow: escrow.key(),
+            authority: authority.key(),
+            nft_mint: escrow.nft_mint,
+            amount: escrow.token_amount,
+        });
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct InitializeSolEscrow<'info> {
-    #[account(mut)]
-    user: Signer<'info>,
-    #[account(init, payer = user, mint::decimals = 0, mint::authority = user, mint::freeze_authority = user)]
-    nft_mint: Account<'info, Mint>,
-    #[account(init, payer = user, space = Escrow::LEN, seeds = ["escrow".as_bytes(), user.key().as_ref(), user_escrow_counter.counter.to_le_bytes().as_ref()], bump,)]
-    pub escrow: Account<'info, Escrow>,
-    #[account(mut, seeds = [b"counter", user.key().as_ref()], bump = user_escrow_counter.bump)]
-    pub user_escrow_counter: Account<'info, UserEscrowCounter>,
-    token_program: Program<'info, Token>,
-    rent: Sysvar<'info, Rent>,
-    system_program: Program<'info, System>,
-}
+    pub fn make_dead_man_switch(ctx: Context<ConfigureEscrow>, beneficiary: Pubkey, heartbeat_interval: i64) -> Result<()> {
+        if heartbeat_interval <= 0 {
+            return err!(CustomError::InvalidHeartbeatInterval);
+        }
+        let escrow = &mut ctx.accounts.escrow;
+        if escrow.kind != EscrowKind::Standard {
+            return err!(CustomError::EscrowKindAlreadySet);
+        }
+        let now = Clock::get()?.unix_timestamp;
+        let claimable_at = now.checked_add(heartbeat_interval).ok_or(CustomError::MathOverflow)?;
+        escrow.kind = EscrowKind::DeadManSwitch { beneficiary, heartbeat_interval, last_heartbeat: now };
+        emit!(Heartbeat {
+            escrow: escrow.key(),
+            last_heartbeat: now,
+            claimable_at,
+        });
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct GetNFT<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
-    #[account(mut, constraint = nft_mint.key() == escrow.nft_mint)]
-    pub nft_mint: Account<'info, Mint>,
-    #[account(init, payer = user, space = TokenMetadata::LEN, seeds = [b"metadata", nft_mint.key().as_ref()], bump)]
-    pub metadata_account: Account<'info, TokenMetadata>,
-    #[account(mut, seeds = ["escrow".as_bytes(), escrow.authority.as_ref(), user_escrow_counter.previous_counter.to_le_bytes().as_ref()], bump = escrow.bump,)]
-    pub escrow: Account<'info, Escrow>,
-    #[account(mut, seeds = [b"counter", user.key().as_ref()], bump = user_escrow_counter.bump)]
-    pub user_escrow_counter: Account<'info, UserEscrowCounter>,
-    #[account(init, payer = user, associated_token::mint = nft_mint, associated_token::authority = user,)]
-    pub user_nft_ata: Account<'info, TokenAccount>,
-    system_program: Program<'info, System>,
-    token_program: Program<'info, Token>,
-    associated_token_program: Program<'info, AssociatedToken>,
-}
+    pub fn heartbeat(ctx: Context<SendHeartbeat>) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        let escrow_key = escrow.key();
+        let now = Clock::get()?.unix_timestamp;
+        match &mut escrow.kind {
+            EscrowKind::DeadManSwitch { heartbeat_interval, last_heartbeat, .. } => {
+                let claimable_at = now.checked_add(*heartbeat_interval).ok_or(CustomError::MathOverflow)?;
+                *last_heartbeat = now;
+                emit!(Heartbeat {
+                    escrow: escrow_key,
+                    last_heartbeat: now,
+                    claimable_at,
+                });
+            }
+            _ => return err!(CustomError::NotDeadManSwitchEscrow),
+        }
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct Retrieve<'info> {
-    pub user: Signer<'info>,
-    #[account(mut, constraint = nft_mint.key() == escrow.nft_mint)]
-    pub nft_mint: Account<'info, Mint>, 
-    #[account(mut, close = user, seeds= [b"metadata", nft_mint.key().as_ref()], bump = metadata_account.bump)]
-    pub metadata_account: Account<'info, TokenMetadata>,
-    #[account(mut, close = user, seeds = [b"escrow", escrow.authority.as_ref(), metadata_account.escrow_number.to_le_bytes().as_ref()], bump = escrow.bump,)]
-    pub escrow: Account<'info, Escrow>,
-    #[account(mut, constraint = user_nft_ata.mint == escrow.nft_mint)]
-    pub user_nft_ata: Account<'info, TokenAccount>,
-    #[account(mut, constraint = escrow_token_ata.key() == user_nft_ata.key() || escrow_token_ata.key() == escrow.escrow_token_ata)]
-    pub escrow_token_ata: Account <'info, TokenAccount>,
-    #[account(mut, constraint = user_token_ata.key() == user_nft_ata.key() || user_token_ata.mint == escrow.token_mint)]
-    pub user_token_ata: Account<'info, TokenAccount>,
-    token_program: Program<'info, Token>,
-    system_program: Program<'info, System>,
-}
+    pub fn claim_inheritance(ctx: Context<ClaimInheritance>) -> Result<()> {
+        sync_rewards(&mut ctx.accounts.escrow, ctx.accounts.mint_policy.as_ref(), ctx.accounts.rewards_pool.as_mut(), Clock::get()?.unix_timestamp, true)?;
+        let escrow = & ctx.accounts.escrow;
+        let beneficiary = & ctx.accounts.beneficiary;
+        match escrow.kind {
+            EscrowKind::DeadManSwitch { beneficiary: expected, heartbeat_interval, last_heartbeat } => {
+                if beneficiary.key() != expected {
+                    return err!(CustomError::Unauthorized);
+                }
+                let claimable_at = last_heartbeat.checked_add(heartbeat_interval).ok_or(CustomError::MathOverflow)?;
+                if Clock::get()?.unix_timestamp < claimable_at {
+                    return err!(CustomError::HeartbeatNotMissed);
+                }
+            }
+            _ => return err!(CustomError::NotDeadManSwitchEscrow),
+        }
+        let escrow_number = escrow.escrow_number.to_le_bytes();
+        let escrow_seeds: &[&[u8]] = &[b"escrow", escrow.authority.as_ref(), escrow_number.as_ref(), &[escrow.bump]];
 
-// Data structures
+        if escrow.nft_acquired {
+            msg!("Freezing the NFT, it no longer represents any assets.");
+            void_nft(escrow, ctx.accounts.nft_mint.as_ref(), ctx.accounts.holder_nft_ata.as_ref(), &ctx.accounts.token_program)?;
+        }
 
-#[account]
-pub struct Escrow {
-    authority: Pubkey,
-    bump: u8,
-    token_mint: Pubkey,
-    escrow_token_ata: Pubkey,
-    token_amount: u64,
+        if escrow.is_solana {
+            msg!("Transfer of $SOL will be handled by escrow account closure.");
+        } else {
+            msg!("Transfering escrowed tokens to the beneficiary.");
+            release_from_escrow(
+                escrow,
+                escrow_seeds,
+                escrow.token_amount,
+                &beneficiary.to_account_info(),
+                ctx.accounts.escrow_token_ata.as_ref(),
+                ctx.accounts.beneficiary_token_ata.as_ref(),
+                &ctx.accounts.token_program,
+            )?;
+            ctx.accounts.mint_policy.as_mut().ok_or(CustomError::MissingMintPolicy)?.record_withdrawal(escrow.token_amount);
+            close_escrow_token_ata(escrow, escrow_seeds, ctx.accounts.escrow_token_ata.as_ref().unwrap(), &beneficiary.to_account_info(), &ctx.accounts.token_program)?;
+        }
+        pay_owed_rewards(escrow, ctx.accounts.rewards_pool.as_ref(), ctx.accounts.reward_vault.as_deref(), ctx.accounts.beneficiary_reward_ata.as_deref(), beneficiary.key(), &ctx.accounts.token_program)?;
+        emit!(InheritanceClaimed {
+            escrow: escrow.key(),
+            authority: escrow.authority,
+            beneficiary: beneficiary.key(),
+            amount: escrow.token_amount,
+        });
+        Ok(())
+    }
+
+    pub fn lock_with_secret(ctx: Context<ConfigureEscrow>, claim_key: Pubkey, reclaim_after: i64) -> Result<()> {
+        if reclaim_after <= Clock::get()?.unix_timestamp {
+            return err!(CustomError::InvalidExpiry);
+        }
+        let escrow = &mut ctx.accounts.escrow;
+        if escrow.kind != EscrowKind::Standard {
+            return err!(CustomError::EscrowKindAlreadySet);
+        }
+        escrow.kind = EscrowKind::HashLock { claim_key, reclaim_after };
+        msg!("Escrow {} can now be claimed with its secret.", escrow.key());
+        Ok(())
+    }
+
+    pub fn claim_with_secret(ctx: Context<ClaimWithSecret>) -> Result<()> {
+        let escrow = & ctx.accounts.escrow;
+        let claimant = & ctx.accounts.claimant;
+        // The secret never goes on-chain: the claimant signs their own address with the keypair derived from it,
+        // so a copied transaction cannot be replayed for somebody else
+        match escrow.kind {
+            EscrowKind::HashLock { claim_key, .. } => {
+                let claim = SecretClaim { escrow: escrow.key(), claimant: claimant.key() };
+                verify_voucher_signature(&ctx.accounts.instructions.to_account_info(), &claim_key, &claim.try_to_vec()?)
+                    .map_err(|_| error!(CustomError::InvalidSecret))?;
+            }
+            _ => return err!(CustomError::NotHashLockEscrow),
+        }
+        let escrow_number = escrow.escrow_number.to_le_bytes();
+        let escrow_seeds: &[&[u8]] = &[b"escrow", escrow.authority.as_ref(), escrow_number.as_ref(), &[escrow.bump]];
+
+        msg!("Transfering the escrowed assets to the claimant.");
+        release_from_escrow(
+            escrow,
+            escrow_seeds,
+            escrow.token_amount,
+            &claimant.to_account_info(),
+            ctx.accounts.escrow_token_ata.as_ref(),
+            ctx.accounts.claimant_token_ata.as_ref(),
+            &ctx.accounts.token_program,
+        )?;
+        if !escrow.is_solana {
+            ctx.accounts.mint_policy.as_mut().ok_or(CustomError::MissingMintPolicy)?.record_withdrawal(escrow.token_amount);
+            // The depositor paid the rent, so they get it back
+            close_escrow_token_ata(escrow, escrow_seeds, ctx.accounts.escrow_token_ata.as_ref().unwrap(), &ctx.accounts.authority.to_account_info(), &ctx.accounts.token_program)?;
+        }
+        emit!(SecretEscrowClaimed {
+            escrow: escrow.key(),
+            claimant: claimant.key(),
+            amount: escrow.token_amount,
+        });
+        Ok(())
+    }
+
+    pub fn reclaim_secret_escrow(ctx: Context<ReclaimSecretEscrow>) -> Result<()> {
+        let escrow = & ctx.accounts.escrow;
+        let authority = & ctx.accounts.authority;
+        match escrow.kind {
+            EscrowKind::HashLock { reclaim_after, .. } => {
+                if Clock::get()?.unix_timestamp < reclaim_after {
+                    return err!(CustomError::ReclaimTooEarly);
+                }
+            }
+            _ => return err!(CustomError::NotHashLockEscrow),
+        }
+        let escrow_number = escrow.escrow_number.to_le_bytes();
+        let escrow_seeds: &[&[u8]] = &[b"escrow", escrow.authority.as_ref(), escrow_number.as_ref(), &[escrow.bump]];
+
+        if escrow.is_solana {
+            msg!("Return of $SOL will be handled by escrow account closure.");
+        } else {
+            msg!("Returning escrowed tokens to the depositor.");
+            release_from_escrow(
+                escrow,
+                escrow_seeds,
+                escrow.token_amount,
+                &authority.to_account_info(),
+                ctx.accounts.escrow_token_ata.as_ref(),
+                ctx.accounts.authority_token_ata.as_ref(),
+                &ctx.accounts.token_program,
+            )?;
+            ctx.accounts.mint_policy.as_mut().ok_or(CustomError::MissingMintPolicy)?.record_withdrawal(escrow.token_amount);
+            close_escrow_token_ata(escrow, escrow_seeds, ctx.accounts.escrow_token_ata.as_ref().unwrap(), &authority.to_account_info(), &ctx.accounts.token_program)?;
+        }
+        emit!(SecretEscrowReclaimed {
+            escrow: escrow.key(),
+            authority: authority.key(),
+            amount: escrow.token_amount,
+        });
+        Ok(())
+    }
+
+    pub fn redeem_voucher(ctx: Context<RedeemVoucher>, amount: u64, nonce: u64, expiry: i64) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let escrow = &mut ctx.accounts.escrow;
+        let recipient = & ctx.accounts.recipient;
+        if escrow.kind != EscrowKind::Standard {
+            return err!(CustomError::VoucherNotSupported);
+        }
+        escrow.check_release(ctx.remaining_accounts, now)?;
+        if now >= expiry {
+            return err!(CustomError::VoucherExpired);
+        }
+        if nonce < escrow.voucher_nonce {
+            return err!(CustomError::VoucherAlreadyUsed);
+        }
+        if amount == 0 || amount > escrow.token_amount {
+            return err!(CustomError::InvalidVoucherAmount);
+        }
+
+        msg!("Verifying the voucher signature.");
+        let voucher = Voucher { escrow: escrow.key(), recipient: recipient.key(), amount, nonce, expiry };
+        verify_voucher_signature(&ctx.accounts.instructions.to_account_info(), &escrow.authority, &voucher.try_to_vec()?)?;
+
+        msg!("Releasing {} to the voucher recipient.", amount);
+        let escrow_number = escrow.escrow_number.to_le_bytes();
+        release_from_escrow(
+            escrow,
+            &[b"escrow", escrow.authority.as_ref(), escrow_number.as_ref(), &[escrow.bump]],
+            amount,
+            &recipient.to_account_info(),
+            ctx.accounts.escrow_token_ata.as_ref(),
+            ctx.accounts.recipient_token_ata.as_ref(),
+            &ctx.accounts.token_program,
+        )?;
+        if !escrow.is_solana {
+            ctx.accounts.mint_policy.as_mut().ok_or(CustomError::MissingMintPolicy)?.record_withdrawal(amount);
+        }
+        escrow.token_amount -= amount;
+        sync_rewards(escrow, ctx.accounts.mint_policy.as_ref(), ctx.accounts.rewards_pool.as_mut(), now, false)?;
+        escrow.voucher_nonce = nonce.checked_add(1).ok_or(CustomError::MathOverflow)?;
+        if let Some(metadata_account) = ctx.accounts.metadata_account.as_mut() {
+            metadata_account.escrowed_amount = escrow.token_amount;
+        }
+        emit!(VoucherRedeemed {
+            escrow: escrow.key(),
+            recipient: recipient.key(),
+            amount,
+            nonce,
+            remaining: escrow.token_amount,
+        });
+        Ok(())
+    }
+
+    pub fn start_stream(ctx: Context<ConfigureEscrow>, recipient: Pubkey, start_ts: i64, end_ts: i64) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        if escrow.kind != EscrowKind::Standard {
+            return err!(CustomError::EscrowKindAlreadySet);
+        }
+        if start_ts >= end_ts {
+            return err!(CustomError::InvalidStream);
+        }
+        // Informative only, the accrued amount is computed pro rata from the total
+        let rate_per_second = escrow.token_amount / (end_ts - start_ts) as u64;
+        escrow.kind = EscrowKind::Stream { recipient, start_ts, end_ts, rate_per_second, withdrawn_amount: 0 };
+        emit!(StreamStarted {
+            escrow: escrow.key(),
+            sender: escrow.authority,
+            recipient,
+            start_ts,
+            end_ts,
+            rate_per_second,
+        });
+        Ok(())
+    }
+
+    pub fn withdraw_streamed(ctx: Context<WithdrawStreamed>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let escrow = &mut ctx.accounts.escrow;
+        let recipient = & ctx.accounts.recipient;
+        let (withdrawable, withdrawn_amount) = match escrow.kind {
+            EscrowKind::Stream { recipient: expected, start_ts, end_ts, withdrawn_amount, .. } => {
+                if recipient.key() != expected {
+                    return err!(CustomError::Unauthorized);
+                }
+                let total_amount = escrow.token_amount + withdrawn_amount;
+                (streamed_amount(total_amount, start_ts, end_ts, now) - withdrawn_amount, withdrawn_amount)
+            }
+            _ => return err!(CustomError::NotStreamEscrow),
+        };
+        if withdrawable == 0 {
+            return err!(CustomError::NothingToClaim);
+        }
+
+        msg!("Withdrawing {} streamed to the recipient.", withdrawable);
+        let (escrow_authority, escrow_number, escrow_bump) = (escrow.authority, escrow.escrow_number.to_le_bytes(), [escrow.bump]);
+        let escrow_seeds: &[&[u8]] = &[b"escrow", escrow_authority.as_ref(), escrow_number.as_ref(), &escrow_bump];
+        release_from_escrow(
+            escrow,
+            escrow_seeds,
+            withdrawable,
+            &recipient.to_account_info(),
+            ctx.accounts.escrow_token_ata.as_ref(),
+            ctx.accounts.recipient_token_ata.as_ref(),
+            &ctx.accounts.token_program,
+        )?;
+        if !escrow.is_solana {
+            ctx.accounts.mint_policy.as_mut().ok_or(CustomError::MissingMintPolicy)?.record_withdrawal(withdrawable);
+        }
+        escrow.token_amount -= withdrawable;
+        if let EscrowKind::Stream { withdrawn_amount: stored, .. } = &mut escrow.kind {
+            *stored = withdrawn_amount + withdrawable;
+        }
+        emit!(StreamWithdrawn {
+            escrow: escrow.key(),
+            recipient: recipient.key(),
+            amount: withdrawable,
+            remaining: escrow.token_amount,
+        });
+
+        if escrow.token_amount == 0 {
+            msg!("The stream is fully paid out, closing the escrow.");
+            if !escrow.is_solana {
+                let escrow_token_ata = ctx.accounts.escrow_token_ata.as_ref().ok_or(CustomError::InvalidEscrowToken)?;
+                close_escrow_token_ata(escrow, escrow_seeds, escrow_token_ata, &ctx.accounts.authority.to_account_info(), &ctx.accounts.token_program)?;
+            }
+            escrow.close(ctx.accounts.authority.to_account_info())?;
+        }
+        Ok(())
+    }
+
+    pub fn cancel_stream(ctx: Context<CancelStream>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let escrow = & ctx.accounts.escrow;
+        let authority = & ctx.accounts.authority;
+        let recipient = & ctx.accounts.recipient;
+        let recipient_amount = match escrow.kind {
+            EscrowKind::Stream { recipient: expected, start_ts, end_ts, withdrawn_amount, .. } => {
+                if recipient.key() != expected {
+                    return err!(CustomError::Unauthorized);
+                }
+                let total_amount = escrow.token_amount + withdrawn_amount;
+                streamed_amount(total_amount, start_ts, end_ts, now) - withdrawn_amount
+            }
+            _ => return err!(CustomError::NotStreamEscrow),
+        };
+        let sender_amount = escrow.token_amount - recipient_amount;
+        let escrow_number = escrow.escrow_number.to_le_bytes();
+        let escrow_seeds: &[&[u8]] = &[b"escrow", escrow.authority.as_ref(), escrow_number.as_ref(), &[escrow.bump]];
+
+        if recipient_amount > 0 {
+            msg!("Paying {} accrued to the recipient.", recipient_amount);
+            release_from_escrow(escrow, escrow_seeds, recipient_amount, &recipient.to_account_info(),
+                ctx.accounts.escrow_token_ata.as_ref(), ctx.accounts.recipient_token_ata.as_ref(), &ctx.accounts.token_program)?;
+        }
+        if escrow.is_solana {
+            msg!("Return of the remaining $SOL will be handled by escrow account closure.");
+        } else {
+            if sender_amount > 0 {
+                release_from_escrow(escrow, escrow_seeds, sender_amount, &authority.to_account_info(),
+                    ctx.accounts.escrow_token_ata.as_ref(), ctx.accounts.authority_token_ata.as_ref(), &ctx.accounts.token_program)?;
+            }
+            ctx.accounts.mint_policy.as_mut().ok_or(CustomError::MissingMintPolicy)?.record_withdrawal(escrow.token_amount);
+            let escrow_token_ata = ctx.accounts.escrow_token_ata.as_ref().ok_or(CustomError::InvalidEscrowToken)?;
+            close_escrow_token_ata(escrow, escrow_seeds, escrow_token_ata, &authority.to_account_info(), &ctx.accounts.token_program)?;
+        }
+        emit!(StreamCancelled {
+            escrow: escrow.key(),
+            recipient_amount,
+            sender_amount,
+        });
+        Ok(())
+    }
+
+    pub fn register_recurring_deposit(ctx: Context<RegisterRecurringDeposit>, amount: u64, interval: i64, instalments: u64) -> Result<()> {
+        if amount == 0 || interval <= 0 || interval > MAX_RECURRING_INTERVAL || instalments == 0 {
+            return err!(CustomError::InvalidRecurringPlan);
+        }
+        if ctx.accounts.escrow.kind != EscrowKind::Standard || ctx.accounts.escrow.is_solana {
+            return err!(CustomError::RecurringNotSupported);
+        }
+        let allowance = amount.checked_mul(instalments).ok_or(CustomError::MathOverflow)?;
+
+        msg!("Approving the escrow as a delegate of the user's token account.");
+        anchor_spl::token::approve(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                anchor_spl::token::Approve {
+                    to: ctx.accounts.source_token_ata.to_account_info(),
+                    delegate: ctx.accounts.escrow.to_account_info(),
+                    authority: ctx.accounts.user.to_account_info(),
+                },
+            ), allowance)?;
+
+        let plan = &mut ctx.accounts.plan;
+        plan.escrow = ctx.accounts.escrow.key();
+        plan.owner = ctx.accounts.user.key();
+        plan.source_token_ata = ctx.accounts.source_token_ata.key();
+        plan.amount = amount;
+        plan.interval = interval;
+        plan.next_deposit_at = Clock::get()?.unix_timestamp.checked_add(interval).ok_or(CustomError::MathOverflow)?;
+        plan.instalments_remaining = instalments;
+        plan.bump = *ctx.bumps.get("plan").unwrap();
+        emit!(RecurringDepositRegistered {
+            escrow: plan.escrow,
+            amount,
+            interval,
+            instalments,
+        });
+        Ok(())
+    }
+
+    pub fn crank_deposit(ctx: Context<CrankDeposit>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let escrow = &mut ctx.accounts.escrow;
+        let plan = &mut ctx.accounts.plan;
+        if plan.instalments_remaining == 0 {
+            return err!(CustomError::RecurringPlanFinished);
+        }
+        if now < plan.next_deposit_at {
+            return err!(CustomError::InstalmentNotDue);
+        }
+        if escrow.kind != EscrowKind::Standard {
+            return err!(CustomError::RecurringNotSupported);
+        }
+        let escrow_amount = escrow.token_amount.checked_add(plan.amount).ok_or(CustomError::MathOverflow)?;
+        ctx.accounts.mint_policy.record_deposit(plan.amount, escrow_amount)?;
+
+        msg!("Pulling the instalment into the escrow's token account.");
+        let escrow_number = escrow.escrow_number.to_le_bytes();
+        anchor_spl::token::transfer(
+            CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(),
+            anchor_spl::token::Transfer {
+                from: ctx.accounts.source_token_ata.to_account_info(),
+                to: ctx.accounts.escrow_token_ata.to_account_info(),
+                authority: escrow.to_account_info(),
+            },
+            &[&[b"escrow", escrow.authority.as_ref(), escrow_number.as_ref(), &[escrow.bump]]],
+            ),
+            plan.amount
+        )?;
+        escrow.token_amount = escrow_amount;
+        sync_rewards(escrow, Some(&ctx.accounts.mint_policy), ctx.accounts.rewards_pool.as_mut(), now, false)?;
+        if let Some(metadata_account) = ctx.accounts.metadata_account.as_mut() {
+            metadata_account.escrowed_amount = escrow_amount;
+        }
+        // Missed intervals are not caught up, the next instalment is due one interval from now
+        plan.next_deposit_at = now.checked_add(plan.interval).ok_or(CustomError::MathOverflow)?;
+        plan.instalments_remaining -= 1;
+        emit!(RecurringDepositPulled {
+            escrow: escrow.key(),
+            amount: plan.amount,
+            token_amount: escrow_amount,
+            instalments_remaining: plan.instalments_remaining,
+        });
+        Ok(())
+    }
+
+    pub fn cancel_recurring_deposit(ctx: Context<CancelRecurringDeposit>) -> Result<()> {
+        msg!("Revoking the escrow's delegation.");
+        anchor_spl::token::revoke(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                anchor_spl::token::Revoke {
+                    source: ctx.accounts.source_token_ata.to_account_info(),
+                    authority: ctx.accounts.user.to_account_info(),
+                },
+            ))?;
+        msg!("Recurring plan of escrow {} was cancelled.", ctx.accounts.plan.escrow);
+        Ok(())
+    }
+
+    pub fn split_escrow(ctx: Context<SplitEscrow>, amount: u64) -> Result<()> {
+        let accounts = ctx.accounts;
+        let user = & accounts.user;
+        let escrow = &mut accounts.escrow;
+        if !escrow.is_plain() {
+            return err!(CustomError::SplitNotSupported);
+        }
+        if amount == 0 || amount >= escrow.token_amount {
+            return err!(CustomError::InvalidSplitAmount);
+        }
+
+        msg!("Burning the NFT.");
+        burn_key(escrow, &accounts.nft_mint, &accounts.user_nft_ata, user, &accounts.token_program)?;
+
+        msg!("Updating user's escrow counter.");
+        let user_escrow_counter = &mut accounts.user_escrow_counter;
+        user_escrow_counter.previous_counter = user_escrow_counter.counter;
+        user_escrow_counter.counter += 1;
+
+        msg!("Initializing the split escrow data account {}.", accounts.split_escrow.key());
+        let split_escrow = &mut accounts.split_escrow;
+        split_escrow.bump = *ctx.bumps.get("split_escrow").unwrap();
+        split_escrow.authority = user.key();
+        split_escrow.token_mint = escrow.token_mint;
+        split_escrow.token_amount = amount;
+        split_escrow.nft_mint = accounts.split_nft_mint.key();
+        split_escrow.nft_acquired = false;
+        split_escrow.is_solana = escrow.is_solana;
+        split_escrow.kind = EscrowKind::Standard;
+        split_escrow.escrow_number = user_escrow_counter.previous_counter;
+        split_escrow.arbiter = None;
+        split_escrow.release_approved = false;
+        split_escrow.signers = escrow.signers.clone();
+        split_escrow.threshold = escrow.threshold;
+        split_escrow.expires_at = None;
+        split_escrow.voucher_nonce = 0;
+        split_escrow.soulbound = escrow.soulbound;
+        // The accrued rewards stay with the original escrow, the split escrow is staked once its NFT is minted
+        split_escrow.reward_per_token_paid = 0;
+        split_escrow.reward_stake = 0;
+        split_escrow.rewards_owed = 0;
+
+        let escrow_number = escrow.escrow_number.to_le_bytes();
+        let escrow_seeds: &[&[u8]] = &[b"escrow", escrow.authority.as_ref(), escrow_number.as_ref(), &[escrow.bump]];
+        if escrow.is_solana {
+            msg!("Moving {} lamports to the split escrow.", amount);
+            **escrow.to_account_info().try_borrow_mut_lamports()? -= amount;
+            **split_escrow.to_account_info().try_borrow_mut_lamports()? += amount;
+        } else {
+            msg!("Moving {} tokens to the split escrow's token account.", amount);
+            let split_escrow_token_ata = accounts.split_escrow_token_ata.as_ref().ok_or(CustomError::InvalidEscrowToken)?;
+            split_escrow.escrow_token_ata = split_escrow_token_ata.key();
+            anchor_spl::token::transfer(
+                CpiContext::new_with_signer(accounts.token_program.to_account_info(),
+                anchor_spl::token::Transfer {
+                    from: accounts.escrow_token_ata.as_ref().ok_or(CustomError::InvalidEscrowToken)?.to_account_info(),
+                    to: split_escrow_token_ata.to_account_info(),
+                    authority: escrow.to_account_info(),
+                },
+                &[escrow_seeds],
+                ),
+                amount
+            )?;
+        }
+        escrow.token_amount -= amount;
+
+        msg!("Issuing fresh NFTs for both escrows.");
+        escrow.nft_mint = accounts.new_nft_mint.key();
+        mint_key(
+            escrow,
+            KeyMint {
+                metadata_account: &mut accounts.new_metadata_account,
+                metadata_bump: *ctx.bumps.get("new_metadata_account").unwrap(),
+                nft_mint: &accounts.new_nft_mint,
+                recipient_nft_ata: &accounts.new_nft_ata,
+            },
+            accounts.collection.as_deref_mut(),
+            user.key(),
+            user,
+            &accounts.token_program,
+        )?;
+        mint_key(
+            split_escrow,
+            KeyMint {
+                metadata_account: &mut accounts.split_metadata_account,
+                metadata_bump: *ctx.bumps.get("split_metadata_account").unwrap(),
+                nft_mint: &accounts.split_nft_mint,
+                recipient_nft_ata: &accounts.split_nft_ata,
+            },
+            accounts.collection.as_deref_mut(),
+            user.key(),
+            user,
+            &accounts.token_program,
+        )?;
+        let now = Clock::get()?.unix_timestamp;
+        sync_rewards(escrow, accounts.mint_policy.as_deref(), accounts.rewards_pool.as_deref_mut(), now, false)?;
+        sync_rewards(split_escrow, accounts.mint_policy.as_deref(), accounts.rewards_pool.as_deref_mut(), now, false)?;
+        emit!(EscrowSplit {
+            escrow: escrow.key(),
+            split_escrow: split_escrow.key(),
+            holder: user.key(),
+            remaining_amount: escrow.token_amount,
+            split_amount: amount,
+        });
+        Ok(())
+    }
+
+    pub fn merge_escrows(ctx: Context<MergeEscrows>) -> Result<()> {
+        let accounts = ctx.accounts;
+        let user = & accounts.user;
+        let escrow = &mut accounts.escrow;
+        let source_escrow = &mut accounts.source_escrow;
+        if !escrow.is_plain() || !source_escrow.is_plain() {
+            return err!(CustomError::MergeNotSupported);
+        }
+        if escrow.is_solana != source_escrow.is_solana || (!escrow.is_solana && escrow.token_mint != source_escrow.token_mint) {
+            return err!(CustomError::MergeAssetMismatch);
+        }
+        // The merged escrow must not lose the retrieval signers or the soulbound flag of either escrow
+        if escrow.signers != source_escrow.signers || escrow.threshold != source_escrow.threshold || escrow.soulbound != source_escrow.soulbound {
+            return err!(CustomError::MergeNotSupported);
+        }
+        let amount = source_escrow.token_amount;
+        let merged_amount = escrow.token_amount.checked_add(amount).ok_or(CustomError::MathOverflow)?;
+
+        msg!("Burning both NFTs.");
+        burn_key(escrow, &accounts.nft_mint, &accounts.user_nft_ata, user, &accounts.token_program)?;
+        burn_key(source_escrow, &accounts.source_nft_mint, &accounts.source_nft_ata, user, &accounts.token_program)?;
+
+        if escrow.is_solana {
+            msg!("Moving {} lamports into the merged escrow.", amount);
+            **source_escrow.to_account_info().try_borrow_mut_lamports()? -= amount;
+            **escrow.to_account_info().try_borrow_mut_lamports()? += amount;
+        } else {
+            let mint_policy = accounts.mint_policy.as_ref().ok_or(CustomError::MissingMintPolicy)?;
+            if merged_amount > mint_policy.max_per_escrow {
+                return err!(CustomError::DepositTooLarge);
+            }
+            msg!("Moving {} tokens into the merged escrow's token account.", amount);
+            let escrow_token_ata = accounts.escrow_token_ata.as_ref().ok_or(CustomError::InvalidEscrowToken)?;
+            let source_escrow_token_ata = accounts.source_escrow_token_ata.as_ref().ok_or(CustomError::InvalidEscrowToken)?;
+            let source_escrow_number = source_escrow.escrow_number.to_le_bytes();
+            let source_escrow_seeds: &[&[u8]] = &[b"escrow", source_escrow.authority.as_ref(), source_escrow_number.as_ref(), &[source_escrow.bump]];
+            anchor_spl::token::transfer(
+                CpiContext::new_with_signer(accounts.token_program.to_account_info(),
+                anchor_spl::token::Transfer {
+                    from: source_escrow_token_ata.to_account_info(),
+                    to: escrow_token_ata.to_account_info(),
+                    authority: source_escrow.to_account_info(),
+                },
+                &[source_escrow_seeds],
+                ),
+                amount
+            )?;
+            close_escrow_token_ata(source_escrow, source_escrow_seeds, source_escrow_token_ata, &user.to_account_info(), &accounts.token_program)?;
+        }
+        // The unclaimed rewards of the closed source escrow move to the merged escrow
+        let now = Clock::get()?.unix_timestamp;
+        sync_rewards(source_escrow, accounts.mint_policy.as_deref(), accounts.rewards_pool.as_deref_mut(), now, true)?;
+        escrow.rewards_owed = escrow.rewards_owed.saturating_add(source_escrow.rewards_owed);
+        escrow.token_amount = merged_amount;
+        sync_rewards(escrow, accounts.mint_policy.as_deref(), accounts.rewards_pool.as_deref_mut(), now, false)?;
+
+        msg!("Issuing a fresh NFT for the merged escrow.");
+        escrow.nft_mint = accounts.new_nft_mint.key();
+        mint_key(
+            escrow,
+            KeyMint {
+                metadata_account: &mut accounts.new_metadata_account,
+                metadata_bump: *ctx.bumps.get("new_metadata_account").unwrap(),
+                nft_mint: &accounts.new_nft_mint,
+                recipient_nft_ata: &accounts.new_nft_ata,
+            },
+            accounts.collection.as_deref_mut(),
+            user.key(),
+            user,
+            &accounts.token_program,
+        )?;
+        emit!(EscrowsMerged {
+            escrow: escrow.key(),
+            source_escrow: source_escrow.key(),
+            holder: user.key(),
+            token_amount: merged_amount,
+        });
+        Ok(())
+    }
+
+    pub fn rotate_key(ctx: Context<RotateKey>) -> Result<()> {
+        let accounts = ctx.accounts;
+        let user = & accounts.user;
+        let escrow = &mut accounts.escrow;
+        let old_nft_mint = escrow.nft_mint;
+
+        msg!("Burning the old NFT.");
+        burn_key(escrow, &accounts.nft_mint, &accounts.user_nft_ata, user, &accounts.token_program)?;
+
+        msg!("Issuing a fresh NFT under a new mint.");
+        escrow.nft_mint = accounts.new_nft_mint.key();
+        mint_key(
+            escrow,
+            KeyMint {
+                metadata_account: &mut accounts.new_metadata_account,
+                metadata_bump: *ctx.bumps.get("new_metadata_account").unwrap(),
+                nft_mint: &accounts.new_nft_mint,
+                recipient_nft_ata: &accounts.new_nft_ata,
+            },
+            accounts.collection.as_deref_mut(),
+            user.key(),
+            user,
+            &accounts.token_program,
+        )?;
+        emit!(KeyRotated {
+            escrow: escrow.key(),
+            holder: user.key(),
+            old_nft_mint,
+            new_nft_mint: escrow.nft_mint,
+        });
+        Ok(())
+    }
+
+    pub fn make_soulbound(ctx: Context<ConfigureEscrow>) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.soulbound = true;
+        msg!("The NFT of escrow {} will be non-transferable.", escrow.key());
+        Ok(())
+    }
+
+    pub fn list_key(ctx: Context<ListKey>, price_mint: Option<Pubkey>, price: u64) -> Result<()> {
+        if ctx.accounts.escrow.soulbound {
+            return err!(CustomError::KeySoulbound);
+        }
+        let listing = &mut ctx.accounts.listing;
+        listing.seller = ctx.accounts.seller.key();
+        listing.escrow = ctx.accounts.escrow.key();
+        listing.nft_mint = ctx.accounts.nft_mint.key();
+        listing.price_mint = price_mint;
+        listing.price = price;
+        listing.bump = *ctx.bumps.get("listing").unwrap();
+
+        msg!("Moving the NFT to the listing.");
+        anchor_spl::token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                anchor_spl::token::Transfer {
+                    from: ctx.accounts.seller_nft_ata.to_account_info(),
+                    to: ctx.accounts.listing_nft_ata.to_account_info(),
+                    authority: ctx.accounts.seller.to_account_info(),
+                },
+            ), 1)?;
+        emit!(KeyListed {
+            listing: listing.key(),
+            escrow: listing.escrow,
+            seller: listing.seller,
+            price_mint,
+            price,
+        });
+        Ok(())
+    }
+
+    pub fn update_listing_price(ctx: Context<UpdateListing>, price: u64) -> Result<()> {
+        let listing = &mut ctx.accounts.listing;
+        listing.price = price;
+        emit!(ListingPriceUpdated {
+            listing: listing.key(),
+            price,
+        });
+        Ok(())
+    }
+
+    pub fn cancel_listing(ctx: Context<CancelListing>) -> Result<()> {
+        let accounts = ctx.accounts;
+        msg!("Returning the NFT to the seller.");
+        release_held_key(
+            &accounts.listing.to_account_info(),
+            &[b"listing", accounts.listing.nft_mint.as_ref(), &[accounts.listing.bump]],
+            &accounts.listing_nft_ata,
+            &accounts.seller_nft_ata,
+            &accounts.seller.to_account_info(),
+            &accounts.token_program,
+        )?;
+        emit!(ListingCancelled {
+            listing: accounts.listing.key(),
+            seller: accounts.seller.key(),
+        });
+        Ok(())
+    }
+
+    // `expected_price` protects the buyer from a price update landing right before the purchase
+    pub fn buy_listing(ctx: Context<BuyListing>, expected_price: u64) -> Result<()> {
+        let accounts = ctx.accounts;
+        let listing = &accounts.listing;
+        if listing.price != expected_price {
+            return err!(CustomError::ListingPriceChanged);
+        }
+        if accounts.escrow.is_expired(Clock::get()?.unix_timestamp) {
+            return err!(CustomError::EscrowExpired);
+        }
+
+        msg!("Paying {} to the seller.", listing.price);
+        pay_directly(
+            &accounts.buyer,
+            Payment { mint: listing.price_mint, amount: listing.price },
+            &accounts.seller.to_account_info(),
+            accounts.buyer_payment_ata.as_deref(),
+            accounts.seller_payment_ata.as_deref(),
+            &accounts.token_program,
+            &accounts.system_program,
+        )?;
+
+        msg!("Transfering the NFT to the buyer.");
+        release_held_key(
+            &listing.to_account_info(),
+            &[b"listing", listing.nft_mint.as_ref(), &[listing.bump]],
+            &accounts.listing_nft_ata,
+            &accounts.buyer_nft_ata,
+            &accounts.seller.to_account_info(),
+            &accounts.token_program,
+        )?;
+        emit!(ListingSold {
+            listing: listing.key(),
+            escrow: listing.escrow,
+            seller: listing.seller,
+            buyer: accounts.buyer.key(),
+            price_mint: listing.price_mint,
+            price: listing.price,
+        });
+        Ok(())
+    }
+
+    pub fn create_auction(ctx: Context<CreateAuction>, bid_mint: Option<Pubkey>, reserve_price: u64, end_ts: i64) -> Result<()> {
+        if ctx.accounts.escrow.soulbound {
+            return err!(CustomError::KeySoulbound);
+        }
+        if end_ts <= Clock::get()?.unix_timestamp {
+            return err!(CustomError::InvalidAuctionEnd);
+        }
+        let auction = &mut ctx.accounts.auction;
+        auction.seller = ctx.accounts.seller.key();
+        auction.escrow = ctx.accounts.escrow.key();
+        auction.nft_mint = ctx.accounts.nft_mint.key();
+        auction.bid_mint = bid_mint;
+        auction.reserve_price = reserve_price;
+        auction.end_ts = end_ts;
+        auction.highest_bidder = None;
+        auction.highest_bid = 0;
+        auction.bump = *ctx.bumps.get("auction").unwrap();
+
+        msg!("Moving the NFT to the auction.");
+        anchor_spl::token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                anchor_spl::token::Transfer {
+                    from: ctx.accounts.seller_nft_ata.to_account_info(),
+                    to: ctx.accounts.auction_nft_ata.to_account_info(),
+                    authority: ctx.accounts.seller.to_account_info(),
+                },
+            ), 1)?;
+        emit!(AuctionCreated {
+            auction: auction.key(),
+            escrow: auction.escrow,
+            seller: auction.seller,
+            bid_mint,
+            reserve_price,
+            end_ts,
+        });
+        Ok(())
+    }
+
+    pub fn place_bid(ctx: Context<PlaceBid>, amount: u64) -> Result<()> {
+        let accounts = ctx.accounts;
+        let bidder = & accounts.bidder;
+        let auction = &mut accounts.auction;
+        let now = Clock::get()?.unix_timestamp;
+        if now >= auction.end_ts {
+            return err!(CustomError::AuctionEnded);
+        }
+        if accounts.escrow.is_expired(now) {
+            return err!(CustomError::EscrowExpired);
+        }
+        if amount < auction.reserve_price || (auction.highest_bidder.is_some() && amount <= auction.highest_bid) {
+            return err!(CustomError::BidTooLow);
+        }
+
+        msg!("Escrowing the bid of {}.", amount);
+        pay_directly(
+            bidder,
+            Payment { mint: auction.bid_mint, amount },
+            &auction.to_account_info(),
+            accounts.bidder_payment_ata.as_deref(),
+            accounts.bid_vault.as_deref(),
+            &accounts.token_program,
+            &accounts.system_program,
+        )?;
+
+        if let Some(previous_bidder) = auction.highest_bidder {
+            msg!("Refunding the outbid bidder {}.", previous_bidder);
+            let previous_bidder_account = accounts.previous_bidder.as_ref().ok_or(CustomError::InvalidAuctionParty)?;
+            if previous_bidder_account.key() != previous_bidder {
+                return err!(CustomError::InvalidAuctionParty);
+            }
+            pay_out_held_funds(
+                &auction.to_account_info(),
+                &[b"auction", auction.nft_mint.as_ref(), &[auction.bump]],
+                Payment { mint: auction.bid_mint, amount: auction.highest_bid },
+                &previous_bidder_account.to_account_info(),
+                accounts.bid_vault.as_deref(),
+                accounts.previous_bidder_payment_ata.as_deref(),
+                &accounts.token_program,
+            )?;
+        }
+        auction.highest_bidder = Some(bidder.key());
+        auction.highest_bid = amount;
+        emit!(BidPlaced {
+            auction: auction.key(),
+            bidder: bidder.key(),
+            amount,
+        });
+        Ok(())
+    }
+
+    pub fn settle_auction(ctx: Context<SettleAuction>) -> Result<()> {
+        let accounts = ctx.accounts;
+        let auction = & accounts.auction;
+        let now = Clock::get()?.unix_timestamp;
+        if now < auction.end_ts {
+            return err!(CustomError::AuctionNotEnded);
+        }
+        // The escrow may have been refunded, expired or claimed during the auction, its key is worthless then
+        let key_valid = Account::<Escrow>::try_from(&accounts.escrow.to_account_info())
+            .map(|escrow| !escrow.is_expired(now))
+            .unwrap_or(false);
+        let (winner, nft_recipient, bid_recipient) = match auction.highest_bidder {
+            Some(highest_bidder) if key_valid => (Some(highest_bidder), highest_bidder, Some(auction.seller)),
+            Some(highest_bidder) => (None, auction.seller, Some(highest_bidder)),
+            None => (None, auction.seller, None),
+        };
+        if accounts.nft_destination_ata.owner != nft_recipient {
+            return err!(CustomError::InvalidAuctionParty);
+        }
+
+        if let Some(bid_recipient) = bid_recipient {
+            msg!("Paying the highest bid of {} to {}.", auction.highest_bid, bid_recipient);
+            let bid_recipient_account = accounts.bid_recipient.as_ref().ok_or(CustomError::InvalidAuctionParty)?;
+            if bid_recipient_account.key() != bid_recipient {
+                return err!(CustomError::InvalidAuctionParty);
+            }
+            pay_out_held_funds(
+                &auction.to_account_info(),
+                &[b"auction", auction.nft_mint.as_ref(), &[auction.bump]],
+                Payment { mint: auction.bid_mint, amount: auction.highest_bid },
+                &bid_recipient_account.to_account_info(),
+                accounts.bid_vault.as_deref(),
+                accounts.bid_recipient_payment_ata.as_deref(),
+                &accounts.token_program,
+            )?;
+        }
+        let auction_seeds: &[&[u8]] = &[b"auction", auction.nft_mint.as_ref(), &[auction.bump]];
+        if let (Some(bid_mint), Some(bid_vault)) = (auction.bid_mint, accounts.bid_vault.as_ref()) {
+            msg!("Closing the bid vault.");
+            close_held_vault(&auction.to_account_info(), auction_seeds, bid_mint, bid_vault, &accounts.seller.to_account_info(), &accounts.token_program)?;
+        }
+
+        msg!("Transfering the NFT to {}.", nft_recipient);
+        release_held_key(
+            &auction.to_account_info(),
+            auction_seeds,
+            &accounts.auction_nft_ata,
+            &accounts.nft_destination_ata,
+            &accounts.seller.to_account_info(),
+            &accounts.token_program,
+        )?;
+        emit!(AuctionSettled {
+            auction: auction.key(),
+            escrow: auction.escrow,
+            seller: auction.seller,
+            winner,
+            amount: if winner.is_some() { auction.highest_bid } else { 0 },
+        });
+        Ok(())
+    }
+
+    pub fn create_loan_offer(ctx: Context<CreateLoanOffer>, offer_id: u64, terms: LoanTerms) -> Result<()> {
+        let LoanTerms { loan_mint, principal, repayment, duration, collateral_token_mint, min_escrowed_amount } = terms;
+        if principal == 0 || repayment < principal || duration <= 0 {
+            return err!(CustomError::InvalidLoanTerms);
+        }
+        let accounts = ctx.accounts;
+        let offer = &mut accounts.offer;
+        offer.lender = accounts.lender.key();
+        offer.offer_id = offer_id;
+        offer.loan_mint = loan_mint;
+        offer.principal = principal;
+        offer.repayment = repayment;
+        offer.duration = duration;
+        offer.collateral_token_mint = collateral_token_mint;
+        offer.min_escrowed_amount = min_escrowed_amount;
+        offer.bump = *ctx.bumps.get("offer").unwrap();
+
+        msg!("Depositing the loan of {} into the offer.", principal);
+        pay_directly(
+            &accounts.lender,
+            Payment { mint: loan_mint, amount: principal },
+            &offer.to_account_info(),
+            accounts.lender_payment_ata.as_deref(),
+            accounts.offer_vault.as_deref(),
+            &accounts.token_program,
+            &accounts.system_program,
+        )?;
+        emit!(LoanOfferCreated {
+            offer: offer.key(),
+            lender: offer.lender,
+            loan_mint,
+            principal,
+            repayment,
+            duration,
+        });
+        Ok(())
+    }
+
+    pub fn cancel_loan_offer(ctx: Context<CancelLoanOffer>) -> Result<()> {
+        let accounts = ctx.accounts;
+        let offer = & accounts.offer;
+        let lender = accounts.lender.to_account_info();
+        let offer_id = offer.offer_id.to_le_bytes();
+        let offer_seeds: &[&[u8]] = &[b"loan_offer", offer.lender.as_ref(), offer_id.as_ref(), &[offer.bump]];
+        msg!("Returning the loan of {} to the lender.", offer.principal);
+        pay_out_held_funds(
+            &offer.to_account_info(),
+            offer_seeds,
+            Payment { mint: offer.loan_mint, amount: offer.principal },
+            &lender,
+            accounts.offer_vault.as_deref(),
+            accounts.lender_payment_ata.as_deref(),
+            &accounts.token_program,
+        )?;
+        if let (Some(loan_mint), Some(offer_vault)) = (offer.loan_mint, accounts.offer_vault.as_ref()) {
+            close_held_vault(&offer.to_account_info(), offer_seeds, loan_mint, offer_vault, &lender, &accounts.token_program)?;
+        }
+        emit!(LoanOfferCancelled {
+            offer: offer.key(),
+            lender: offer.lender,
+        });
+        Ok(())
+    }
+
+    pub fn borrow(ctx: Context<Borrow>) -> Result<()> {
+        let accounts = ctx.accounts;
+        let offer = & accounts.offer;
+        let escrow = & accounts.escrow;
+        let metadata_account = & accounts.metadata_account;
+        let now = Clock::get()?.unix_timestamp;
+        let due_ts = now.checked_add(offer.duration).ok_or(CustomError::MathOverflow)?;
+        if escrow.soulbound {
+            return err!(CustomError::KeySoulbound);
+        }
+        // Only plain escrows whose whole amount the key alone can retrieve are accepted as collateral
+        if escrow.kind != EscrowKind::Standard || escrow.arbiter.is_some() || escrow.threshold > 0 {
+            return err!(CustomError::CollateralNotAccepted);
+        }
+        // The key is valued by its metadata, which must match the escrow, and must stay retrievable for the whole loan
+        if metadata_account.escrowed_token_mint != escrow.token_mint || metadata_account.escrowed_amount != escrow.token_amount {
+            return err!(CustomError::CollateralNotAccepted);
+        }
+        if metadata_account.escrowed_token_mint != offer.collateral_token_mint
+            || metadata_account.escrowed_amount < offer.min_escrowed_amount
+            || matches!(escrow.expires_at, Some(expires_at) if expires_at <= due_ts) {
+            return err!(CustomError::CollateralNotAccepted);
+        }
+
+        let loan = &mut accounts.loan;
+        loan.borrower = accounts.borrower.key();
+        loan.lender = offer.lender;
+        loan.escrow = escrow.key();
+        loan.nft_mint = accounts.nft_mint.key();
+        loan.loan_mint = offer.loan_mint;
+        loan.principal = offer.principal;
+        loan.repayment = offer.repayment;
+        loan.due_ts = due_ts;
+        loan.bump = *ctx.bumps.get("loan").unwrap();
+
+        msg!("Locking the NFT in the loan.");
+        anchor_spl::token::transfer(
+            CpiContext::new(
+                accounts.token_program.to_account_info(),
+                anchor_spl::token::Transfer {
+                    from: accounts.borrower_nft_ata.to_account_info(),
+                    to: accounts.loan_nft_ata.to_account_info(),
+                    authority: accounts.borrower.to_account_info(),
+                },
+            ), 1)?;
+
+        msg!("Paying out the loan of {} to the borrower.", offer.principal);
+        let offer_id = offer.offer_id.to_le_bytes();
+        let offer_seeds: &[&[u8]] = &[b"loan_offer", offer.lender.as_ref(), offer_id.as_ref(), &[offer.bump]];
+        pay_out_held_funds(
+            &offer.to_account_info(),
+            offer_seeds,
+            Payment { mint: offer.loan_mint, amount: offer.principal },
+            &accounts.borrower.to_account_info(),
+            accounts.offer_vault.as_deref(),
+            accounts.borrower_payment_ata.as_deref(),
+            &accounts.token_program,
+        )?;
+        if let (Some(loan_mint), Some(offer_vault)) = (offer.loan_mint, accounts.offer_vault.as_ref()) {
+            close_held_vault(&offer.to_account_info(), offer_seeds, loan_mint, offer_vault, &accounts.lender.to_account_info(), &accounts.token_program)?;
+        }
+        emit!(LoanTaken {
+            loan: loan.key(),
+            offer: offer.key(),
+            escrow: loan.escrow,
+            borrower: loan.borrower,
+            lender: loan.lender,
+            principal: loan.principal,
+            repayment: loan.repayment,
+            due_ts,
+        });
+        Ok(())
+    }
+
+    pub fn repay_loan(ctx: Context<RepayLoan>) -> Result<()> {
+        let accounts = ctx.accounts;
+        let loan = & accounts.loan;
+        msg!("Paying {} back to the lender.", loan.repayment);
+        pay_directly(
+            &accounts.borrower,
+            Payment { mint: loan.loan_mint, amount: loan.repayment },
+            &accounts.lender.to_account_info(),
+            accounts.borrower_payment_ata.as_deref(),
+            accounts.lender_payment_ata.as_deref(),
+            &accounts.token_program,
+            &accounts.system_program,
+        )?;
+
+        msg!("Returning the NFT to the borrower.");
+        release_held_key(
+            &loan.to_account_info(),
+            &[b"loan", loan.nft_mint.as_ref(), &[loan.bump]],
+            &accounts.loan_nft_ata,
+            &accounts.borrower_nft_ata,
+            &accounts.borrower.to_account_info(),
+            &accounts.token_program,
+        )?;
+        emit!(LoanRepaid {
+            loan: loan.key(),
+            borrower: loan.borrower,
+            lender: loan.lender,
+            repayment: loan.repayment,
+        });
+        Ok(())
+    }
+
+    pub fn claim_collateral(ctx: Context<ClaimCollateral>) -> Result<()> {
+        let accounts = ctx.accounts;
+        let loan = & accounts.loan;
+        if Clock::get()?.unix_timestamp < loan.due_ts {
+            return err!(CustomError::LoanNotDue);
+        }
+        msg!("Transfering the NFT to the lender.");
+        release_held_key(
+            &loan.to_account_info(),
+            &[b"loan", loan.nft_mint.as_ref(), &[loan.bump]],
+            &accounts.loan_nft_ata,
+            &accounts.lender_nft_ata,
+            &accounts.borrower.to_account_info(),
+            &accounts.token_program,
+        )?;
+        emit!(CollateralClaimed {
+            loan: loan.key(),
+            escrow: loan.escrow,
+            borrower: loan.borrower,
+            lender: loan.lender,
+        });
+        Ok(())
+    }
+
+    pub fn setup_milestones(ctx: Context<ConfigureEscrow>, payee: Pubkey, amounts: Vec<u64>) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        if escrow.kind != EscrowKind::Standard {
+            return err!(CustomError::EscrowKindAlreadySet);
+        }
+        if amounts.is_empty() || amounts.len() > MAX_MILESTONES {
+            return err!(CustomError::InvalidMilestones);
+        }
+        let mut total: u64 = 0;
+        for amount in amounts.iter() {
+            total = total.checked_add(*amount).ok_or(CustomError::MathOverflow)?;
+        }
+        if total != escrow.token_amount {
+            return err!(CustomError::InvalidMilestones);
+        }
+        let milestones: Vec<Milestone> = amounts.iter().map(|amount| Milestone { amount: *amount, status: MilestoneStatus::Pending }).collect();
+        escrow.kind = EscrowKind::Milestone { payee, milestones };
+        emit!(MilestonesCreated {
+            escrow: escrow.key(),
+            payer: escrow.authority,
+            payee,
+            count: amounts.len() as u8,
+            total_amount: total,
+        });
+        Ok(())
+    }
+
+    pub fn submit_milestone(ctx: Context<MilestoneAction>, index: u8) -> Result<()> {
+        let user = ctx.accounts.user.key();
+        let escrow = &mut ctx.accounts.escrow;
+        let escrow_key = escrow.key();
+        let (payee, milestone) = escrow.kind.milestone_mut(index)?;
+        if user != payee {
+            return err!(CustomError::Unauthorized);
+        }
+        if milestone.status != MilestoneStatus::Pending {
+            return err!(CustomError::InvalidMilestoneStatus);
+        }
+        milestone.status = MilestoneStatus::Submitted;
+        emit!(MilestoneSubmitted { escrow: escrow_key, index });
+        Ok(())
+    }
+
+    pub fn approve_milestone(ctx: Context<SettleMilestone>, index: u8) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        if ctx.accounts.user.key() != escrow.authority {
+            return err!(CustomError::Unauthorized);
+        }
+        let escrow_key = escrow.key();
+        let (_, milestone) = escrow.kind.milestone_mut(index)?;
+        // The payer may also release a milestone before the payee submits it
+        if milestone.status != MilestoneStatus::Pending && milestone.status != MilestoneStatus::Submitted {
+            return err!(CustomError::InvalidMilestoneStatus);
+        }
+        milestone.status = MilestoneStatus::Released;
+        let amount = milestone.amount;
+        settle_milestone(ctx.accounts, amount, 0)?;
+        emit!(MilestoneReleased { escrow: escrow_key, index, amount });
+        Ok(())
+    }
+
+    pub fn open_dispute(ctx: Context<MilestoneAction>, index: u8) -> Result<()> {
+        let user = ctx.accounts.user.key();
+        let escrow = &mut ctx.accounts.escrow;
+        let escrow_key = escrow.key();
+        if escrow.arbiter.is_none() {
+            return err!(CustomError::NoArbiter);
+        }
+        let payer = escrow.authority;
+        let (payee, milestone) = escrow.kind.milestone_mut(index)?;
+        if user != payee && user != payer {
+            return err!(CustomError::Unauthorized);
+        }
+        if milestone.status != MilestoneStatus::Pending && milestone.status != MilestoneStatus::Submitted {
+            return err!(CustomError::InvalidMilestoneStatus);
+        }
+        milestone.status = MilestoneStatus::Disputed;
+        emit!(MilestoneDisputed { escrow: escrow_key, index, opened_by: user });
+        Ok(())
+    }
+
+    pub fn resolve_dispute(ctx: Context<SettleMilestone>, index: u8, payee_amount: u64) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        if escrow.arbiter != Some(ctx.accounts.user.key()) {
+            return err!(CustomError::Unauthorized);
+        }
+        let escrow_key = escrow.key();
+        let (_, milestone) = escrow.kind.milestone_mut(index)?;
+        if milestone.status != MilestoneStatus::Disputed {
+            return err!(CustomError::InvalidMilestoneStatus);
+        }
+        if payee_amount > milestone.amount {
+            return err!(CustomError::InvalidSplit);
+        }
+        milestone.status = MilestoneStatus::Resolved;
+        let payer_amount = milestone.amount - payee_amount;
+        settle_milestone(ctx.accounts, payee_amount, payer_amount)?;
+        emit!(MilestoneDisputeResolved { escrow: escrow_key, index, payee_amount, payer_amount });
+        Ok(())
+    }
+
+    pub fn claim_vested(ctx: Context<ClaimVested>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.check_release(ctx.remaining_accounts, now)?;
+        let (start_ts, cliff_ts, end_ts, released_amount) = match escrow.kind {
+            EscrowKind::Vesting { start_ts, cliff_ts, end_ts, released_amount } => (start_ts, cliff_ts, end_ts, released_amount),
+            _ => return err!(CustomError::NotVestingEscrow),
+        };
+        let total_amount = escrow.token_amount + released_amount;
+        let claimable = vested_amount(total_amount, start_ts, cliff_ts, end_ts, now) - released_amount;
+        if claimable == 0 {
+            return err!(CustomError::NothingToClaim);
+        }
+
+        msg!("Releasing {} vested tokens to the NFT holder.", claimable);
+        let escrow_number = ctx.accounts.metadata_account.escrow_number.to_le_bytes();
+        release_from_escrow(
+            escrow,
+            &[b"escrow", escrow.authority.as_ref(), escrow_number.as_ref(), &[escrow.bump]],
+            claimable,
+            &ctx.accounts.user.to_account_info(),
+            ctx.accounts.escrow_token_ata.as_ref(),
+            ctx.accounts.user_token_ata.as_ref(),
+            &ctx.accounts.token_program,
+        )?;
+        if let Some(mint_policy) = ctx.accounts.mint_policy.as_mut() {
+            mint_policy.record_withdrawal(claimable);
+        } else if !escrow.is_solana {
+            return err!(CustomError::MissingMintPolicy);
+        }
+
+        escrow.token_amount -= claimable;
+        sync_rewards(escrow, ctx.accounts.mint_policy.as_ref(), ctx.accounts.rewards_pool.as_mut(), now, false)?;
+        escrow.kind = EscrowKind::Vesting { start_ts, cliff_ts, end_ts, released_amount: released_amount + claimable };
+        ctx.accounts.metadata_account.escrowed_amount = escrow.token_amount;
+        emit!(VestedClaimed {
+            escrow: escrow.key(),
+            holder: ctx.accounts.user.key(),
+            amount: claimable,
+            remaining: escrow.token_amount,
+        });
+        Ok(())
+    }
+
+    pub fn retrieve(ctx: Context<Retrieve>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        sync_rewards(&mut ctx.accounts.escrow, ctx.accounts.mint_policy.as_ref(), ctx.accounts.rewards_pool.as_deref_mut(), now, true)?;
+        // The signer is either the owner of the NFT's token account or its approved delegate
+        let user = & ctx.accounts.user;
+        let holder = & ctx.accounts.holder;
+        let escrow = & ctx.accounts.escrow;
+        escrow.check_release(ctx.remaining_accounts, now)?;
+        if let EscrowKind::Vesting { end_ts, .. } = escrow.kind {
+            // Before the end of the schedule only `claim_vested` can withdraw from the escrow
+            if now < end_ts {
+                return err!(CustomError::VestingNotFinished);
+            }
+        }
+        let user_nft_ata = & ctx.accounts.user_nft_ata;
+        let nft_mint = & ctx.accounts.nft_mint;
+        let escrow_token_ata = & ctx.accounts.escrow_token_ata;
+        let user_token_ata = & ctx.accounts.user_token_ata;
+        if escrow.soulbound {
+            msg!("Thawing the soulbound NFT.");
+            thaw_key(escrow, nft_mint, user_nft_ata, &ctx.accounts.token_program)?;
+        }
+        msg!("Burning the NFT.");
+        anchor_spl::token::burn(
+            CpiContext::new(ctx.accounts.token_program.to_account_info(),
+            anchor_spl::token::Burn {
+                mint: nft_mint.to_account_info(),
+                from: user_nft_ata.to_account_info(),
+                authority: user.to_account_info(), 
+            },),
+            1, // one NFT
+        )?;
+        msg!("Burn successful.");
+        let escrow_number = escrow.escrow_number.to_le_bytes();
+        if escrow.is_solana {
+            msg!("Transfering Solana to the NFT holder.");
+            msg!("Transfer will be handled by escrow account closure.");
+        } else {
+            msg!("Transfering escrowed tokens back to the NFT holder.");
+            anchor_spl::token::transfer(
+                CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(),
+                anchor_spl::token::Transfer {
+                    from: escrow_token_ata.to_account_info(),
+                    to: user_token_ata.to_account_info(),
+                    authority: escrow.to_account_info(),
+                },
+                &[&["escrow".as_bytes(), escrow.authority.as_ref(), escrow_number.as_ref(), &[escrow.bump]]],
+                ),
+                escrow.token_amount
+            )?;
+            msg!("Transfer was successful.");
+
+            pay_owed_rewards(escrow, ctx.accounts.rewards_pool.as_deref(), ctx.accounts.reward_vault.as_deref(), ctx.accounts.holder_reward_ata.as_deref(), holder.key(), &ctx.accounts.token_program)?;
+
+            // Escrows created before the allowlist may hold a mint that was never registered, there is no TVL to update then
+            if let Some(mint_policy) = ctx.accounts.mint_policy.as_mut() {
+                msg!("Updating the mint's total value locked.");
+                mint_policy.record_withdrawal(escrow.token_amount);
+            }
+
+            msg!("Closing the escrow token account (ata).");
+            anchor_spl::token::close_account(
+                CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(),
+                anchor_spl::token::CloseAccount {
+                    account: escrow_token_ata.to_account_info(),
+                    destination: holder.to_account_info(),
+                    authority: escrow.to_account_info(),
+                },
+                &[&["escrow".as_bytes(), escrow.authority.as_ref(), escrow_number.as_ref(), &[escrow.bump]]],
+            ))?;
+            msg!("Escrow token account (ata) was successfuly closed.");
+        }
+        Ok(())
+    }
+}
+
+// Helper functions
+
+pub fn burn_key<'info>(
+    escrow: &Account<'info, Escrow>,
+    nft_mint: &Account<'info, Mint>,
+    user_nft_ata: &Account<'info, TokenAccount>,
+    user: &Signer<'info>,
+    token_program: &Program<'info, Token>,
+) -> Result<()> {
+    if escrow.soulbound {
+        thaw_key(escrow, nft_mint, user_nft_ata, token_program)?;
+    }
+    anchor_spl::token::burn(
+        CpiContext::new(token_program.to_account_info(),
+        anchor_spl::token::Burn {
+            mint: nft_mint.to_account_info(),
+            from: user_nft_ata.to_account_info(),
+            authority: user.to_account_info(),
+        },),
+        1, // one NFT
+    )
+}
+
+// The accounts of a single NFT issued by `mint_key`
+pub struct KeyMint<'a, 'info> {
+    pub metadata_account: &'a mut Account<'info, TokenMetadata>,
+    pub metadata_bump: u8,
+    pub nft_mint: &'a Account<'info, Mint>,
+    pub recipient_nft_ata: &'a Account<'info, TokenAccount>,
+}
+
+// Initializes the NFT metadata account and mints the escrow's NFT into `recipient_nft_ata`
+pub fn mint_key<'info>(
+    escrow: &mut Account<'info, Escrow>,
+    key: KeyMint<'_, 'info>,
+    collection: Option<&mut Account<'info, KeyCollection>>,
+    recipient: Pubkey,
+    user: &Signer<'info>,
+    token_program: &Program<'info, Token>,
+) -> Result<()> {
+    let KeyMint { metadata_account, metadata_bump, nft_mint, recipient_nft_ata } = key;
+    msg!("Initializing NFT metadata account.");
+    metadata_account.bump = metadata_bump;
+    metadata_account.token_title = String::from("Deposit Box Key");
+    metadata_account.escrow_number = escrow.escrow_number;
+    metadata_account.escrowed_token_mint = escrow.token_mint;
+    metadata_account.escrowed_amount = escrow.token_amount;
+    metadata_account.recipient = recipient;
+    // Only this program can write the metadata account, so it is proof of membership on its own
+    if let Some(collection) = collection {
+        metadata_account.collection = collection.collection_mint;
+        collection.minted += 1;
+    }
+
+    msg!("NFT mint: {}", nft_mint.key());
+    msg!("NFT metadata account address: {}", metadata_account.key());
+
+    msg!("Minting a NFT to {}'s associated token account...", recipient);
+    anchor_spl::token::mint_to(
+        CpiContext::new(
+            token_program.to_account_info(),
+            anchor_spl::token::MintTo {
+                mint: nft_mint.to_account_info(),
+                to: recipient_nft_ata.to_account_info(),
+                authority: user.to_account_info(),
+            },
+        ), 1)?;
+    msg!("Minting was successful.");
+    msg!("Revoking the mint authority, no other copy of the key can ever be minted.");
+    anchor_spl::token::set_authority(
+        CpiContext::new(
+            token_program.to_account_info(),
+            anchor_spl::token::SetAuthority {
+                current_authority: user.to_account_info(),
+                account_or_mint: nft_mint.to_account_info(),
+            },
+        ),
+        AuthorityType::MintTokens,
+        None,
+    )?;
+    if escrow.soulbound {
+        msg!("Freezing the soulbound NFT.");
+        freeze_key(escrow, nft_mint, recipient_nft_ata, token_program)?;
+    }
+    msg!("Checkmarking NFT issuance.");
+    escrow.nft_acquired = true;
+    Ok(())
+}
+
+// Linear vesting with a cliff: nothing before `cliff_ts`, everything from `end_ts`
+pub fn vested_amount(total_amount: u64, start_ts: i64, cliff_ts: i64, end_ts: i64, now: i64) -> u64 {
+    if now < cliff_ts {
+        0
+    } else if now >= end_ts {
+        total_amount
+    } else {
+        (total_amount as u128 * (now - start_ts) as u128 / (end_ts - start_ts) as u128) as u64
+    }
+}
+
+// Amount streamed by `now`: linear between `start_ts` and `end_ts`, rounded down
+pub fn streamed_amount(total_amount: u64, start_ts: i64, end_ts: i64, now: i64) -> u64 {
+    if now <= start_ts {
+        0
+    } else if now >= end_ts {
+        total_amount
+    } else {
+        (total_amount as u128 * (now - start_ts) as u128 / (end_ts - start_ts) as u128) as u64
+    }
+}
+
+// Moves part of the escrowed assets out of the escrow, signing with the escrow PDA.
+// $SOL is moved directly between lamport balances (the escrow data account is owned by this program),
+// SPL-tokens are transferred from the escrow token account.
+pub fn release_from_escrow<'info>(
+    escrow: &Account<'info, Escrow>,
+    escrow_seeds: &[&[u8]],
+    amount: u64,
+    sol_recipient: &AccountInfo<'info>,
+    escrow_token_ata: Option<&Account<'info, TokenAccount>>,
+    recipient_token_ata: Option<&Account<'info, TokenAccount>>,
+    token_program: &Program<'info, Token>,
+) -> Result<()> {
+    if escrow.is_solana {
+        **escrow.to_account_info().try_borrow_mut_lamports()? -= amount;
+        **sol_recipient.try_borrow_mut_lamports()? += amount;
+        return Ok(());
+    }
+    let escrow_token_ata = escrow_token_ata.ok_or(CustomError::InvalidEscrowToken)?;
+    let recipient_token_ata = recipient_token_ata.ok_or(CustomError::InvalidUserToken)?;
+    if escrow_token_ata.key() != escrow.escrow_token_ata || recipient_token_ata.mint != escrow.token_mint {
+        return err!(CustomError::InvalidEscrowToken);
+    }
+    anchor_spl::token::transfer(
+        CpiContext::new_with_signer(token_program.to_account_info(),
+        anchor_spl::token::Transfer {
+            from: escrow_token_ata.to_account_info(),
+            to: recipient_token_ata.to_account_info(),
+            authority: escrow.to_account_info(),
+        },
+        &[escrow_seeds],
+        ),
+        amount
+    )
+}
+
+pub fn close_escrow_token_ata<'info>(
+    escrow: &Account<'info, Escrow>,
+    escrow_seeds: &[&[u8]],
+    escrow_token_ata: &Account<'info, TokenAccount>,
+    destination: &AccountInfo<'info>,
+    token_program: &Program<'info, Token>,
+) -> Result<()> {
+    anchor_spl::token::close_account(
+        CpiContext::new_with_signer(token_program.to_account_info(),
+        anchor_spl::token::CloseAccount {
+            account: escrow_token_ata.to_account_info(),
+            destination: destination.clone(),
+            authority: escrow.to_account_info(),
+        },
+        &[escrow_seeds],
+    ))
+}
+
+// Pays a settled milestone out to the payee and/or back to the payer.
+// Once every milestone is settled, the empty escrow (and its token account) is closed to the payer.
+pub fn settle_milestone(accounts: &mut SettleMilestone, payee_amount: u64, payer_amount: u64) -> Result<()> {
+    let escrow = &accounts.escrow;
+    let payee = match &escrow.kind {
+        EscrowKind::Milestone { payee, .. } => *payee,
+        _ => return err!(CustomError::NotMilestoneEscrow),
+    };
+    if accounts.payee.key() != payee {
+        return err!(CustomError::Unauthorized);
+    }
+    let (escrow_authority, escrow_number, escrow_bump) = (escrow.authority, escrow.escrow_number.to_le_bytes(), [escrow.bump]);
+    let escrow_seeds: &[&[u8]] = &[b"escrow", escrow_authority.as_ref(), escrow_number.as_ref(), &escrow_bump];
+    if payee_amount > 0 {
+        release_from_escrow(escrow, escrow_seeds, payee_amount, &accounts.payee.to_account_info(),
+            accounts.escrow_token_ata.as_ref(), accounts.payee_token_ata.as_ref(), &accounts.token_program)?;
+    }
+    if payer_amount > 0 {
+        release_from_escrow(escrow, escrow_seeds, payer_amount, &accounts.authority.to_account_info(),
+            accounts.escrow_token_ata.as_ref(), accounts.authority_token_ata.as_ref(), &accounts.token_program)?;
+    }
+    if !escrow.is_solana {
+        accounts.mint_policy.as_mut().ok_or(CustomError::MissingMintPolicy)?.record_withdrawal(payee_amount + payer_amount);
+    }
+
+    let escrow = &mut accounts.escrow;
+    escrow.token_amount -= payee_amount + payer_amount;
+    if escrow.kind.milestones_settled() {
+        msg!("All milestones are settled, closing the escrow.");
+        if !escrow.is_solana {
+            let escrow_token_ata = accounts.escrow_token_ata.as_ref().ok_or(CustomError::InvalidEscrowToken)?;
+            close_escrow_token_ata(escrow, escrow_seeds, escrow_token_ata, &accounts.authority.to_account_info(), &accounts.token_program)?;
+        }
+        escrow.close(accounts.authority.to_account_info())?;
+    }
+    Ok(())
+}
+
+// Checks that the instruction right before the current one is an Ed25519 program instruction
+// verifying exactly one signature of `signer` over `message`, with all data inside that instruction
+pub fn verify_voucher_signature(instructions: &AccountInfo, signer: &Pubkey, message: &[u8]) -> Result<()> {
+    let current_index = load_current_index_checked(instructions)?;
+    if current_index == 0 {
+        return err!(CustomError::InvalidVoucherSignature);
+    }
+    let ed25519_ix = load_instruction_at_checked((current_index - 1) as usize, instructions)?;
+    if ed25519_ix.program_id != ed25519_program::ID || !ed25519_ix.accounts.is_empty() {
+        return err!(CustomError::InvalidVoucherSignature);
+    }
+    check_ed25519_data(&ed25519_ix.data, signer, message)
+}
+
+// Checks the data of an Ed25519 program instruction: a single signature whose public key and message,
+// both located inside the instruction itself, are `signer` and `message`
+pub fn check_ed25519_data(data: &[u8], signer: &Pubkey, message: &[u8]) -> Result<()> {
+    // Header: number of signatures (u8), padding (u8), then the 7 u16 offsets of the signature
+    if data.len() < 16 || data[0] != 1 {
+        return err!(CustomError::InvalidVoucherSignature);
+    }
+    let read_u16 = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]) as usize;
+    let public_key_offset = read_u16(6);
+    let message_offset = read_u16(10);
+    let message_size = read_u16(12);
+    // Instruction indexes of u16::MAX mean "this instruction"
+    if read_u16(4) != u16::MAX as usize || read_u16(8) != u16::MAX as usize || read_u16(14) != u16::MAX as usize {
+        return err!(CustomError::InvalidVoucherSignature);
+    }
+    let public_key = data.get(public_key_offset..public_key_offset + 32).ok_or(CustomError::InvalidVoucherSignature)?;
+    let signed_message = data.get(message_offset..message_offset + message_size).ok_or(CustomError::InvalidVoucherSignature)?;
+    if public_key != signer.as_ref() || signed_message != message {
+        return err!(CustomError::InvalidVoucherSignature);
+    }
+    Ok(())
+}
+
+// Freezes the token account holding the escrow's NFT (the escrow PDA is the freeze authority),
+// used when the assets leave the escrow without the NFT being burned
+pub fn void_nft<'info>(
+    escrow: &Account<'info, Escrow>,
+    nft_mint: Option<&Account<'info, Mint>>,
+    holder_nft_ata: Option<&Account<'info, TokenAccount>>,
+    token_program: &Program<'info, Token>,
+) -> Result<()> {
+    let nft_mint = nft_mint.ok_or(CustomError::InvalidNftHolder)?;
+    if nft_mint.supply == 0 {
+        // The NFT was burned, and without a mint authority no new one can be minted
+        return Ok(());
+    }
+    let holder_nft_ata = holder_nft_ata.ok_or(CustomError::InvalidNftHolder)?;
+    if holder_nft_ata.is_frozen() {
+        // Soulbound NFTs are frozen already and nobody can thaw them once the escrow is closed
+        return Ok(());
+    }
+    freeze_key(escrow, nft_mint, holder_nft_ata, token_program)
+}
+
+pub fn freeze_key<'info>(
+    escrow: &Account<'info, Escrow>,
+    nft_mint: &Account<'info, Mint>,
+    nft_ata: &Account<'info, TokenAccount>,
+    token_program: &Program<'info, Token>,
+) -> Result<()> {
+    let escrow_number = escrow.escrow_number.to_le_bytes();
+    anchor_spl::token::freeze_account(
+        CpiContext::new_with_signer(token_program.to_account_info(),
+        anchor_spl::token::FreezeAccount {
+            account: nft_ata.to_account_info(),
+            mint: nft_mint.to_account_info(),
+            authority: escrow.to_account_info(),
+        },
+        &[&[b"escrow", escrow.authority.as_ref(), escrow_number.as_ref(), &[escrow.bump]]],
+    ))
+}
+
+pub fn thaw_key<'info>(
+    escrow: &Account<'info, Escrow>,
+    nft_mint: &Account<'info, Mint>,
+    nft_ata: &Account<'info, TokenAccount>,
+    token_program: &Program<'info, Token>,
+) -> Result<()> {
+    let escrow_number = escrow.escrow_number.to_le_bytes();
+    anchor_spl::token::thaw_account(
+        CpiContext::new_with_signer(token_program.to_account_info(),
+        anchor_spl::token::ThawAccount {
+            account: nft_ata.to_account_info(),
+            mint: nft_mint.to_account_info(),
+            authority: escrow.to_account_info(),
+        },
+        &[&[b"escrow", escrow.authority.as_ref(), escrow_number.as_ref(), &[escrow.bump]]],
+    ))
+}
+
+// Moves an NFT held by a listing, auction or loan PDA (the `holder`, signing with `holder_seeds`)
+// out of the PDA's token account and closes that account.
+// A key voided while it was held stays frozen in the PDA's token account, which can be neither emptied nor closed.
+pub fn release_held_key<'info>(
+    holder: &AccountInfo<'info>,
+    holder_seeds: &[&[u8]],
+    holder_nft_ata: &Account<'info, TokenAccount>,
+    destination_nft_ata: &Account<'info, TokenAccount>,
+    rent_destination: &AccountInfo<'info>,
+    token_program: &Program<'info, Token>,
+) -> Result<()> {
+    if holder_nft_ata.is_frozen() {
+        msg!("The NFT has been voided, leaving it behind.");
+        return Ok(());
+    }
+    anchor_spl::token::transfer(
+        CpiContext::new_with_signer(token_program.to_account_info(),
+        anchor_spl::token::Transfer {
+            from: holder_nft_ata.to_account_info(),
+            to: destination_nft_ata.to_account_info(),
+            authority: holder.clone(),
+        },
+        &[holder_seeds],
+        ),
+        1
+    )?;
+    anchor_spl::token::close_account(
+        CpiContext::new_with_signer(token_program.to_account_info(),
+        anchor_spl::token::CloseAccount {
+            account: holder_nft_ata.to_account_info(),
+            destination: rent_destination.clone(),
+            authority: holder.clone(),
+        },
+        &[holder_seeds],
+    ))
+}
+
+// An amount of $SOL (no `mint`) or of SPL-tokens of `mint`, as paid for listings, auctions and loans
+pub struct Payment {
+    pub mint: Option<Pubkey>,
+    pub amount: u64,
+}
+
+// Pays `amount` held by an auction or loan offer PDA (the `holder`, signing with `holder_seeds`) out to `recipient`.
+// $SOL sits directly in the PDA, SPL-tokens of `payment_mint` in the PDA's token account (`vault`).
+pub fn pay_out_held_funds<'info>(
+    holder: &AccountInfo<'info>,
+    holder_seeds: &[&[u8]],
+    payment: Payment,
+    recipient: &AccountInfo<'info>,
+    vault: Option<&Account<'info, TokenAccount>>,
+    recipient_payment_ata: Option<&Account<'info, TokenAccount>>,
+    token_program: &Program<'info, Token>,
+) -> Result<()> {
+    let Payment { mint: payment_mint, amount } = payment;
+    match payment_mint {
+        None => {
+            **holder.try_borrow_mut_lamports()? -= amount;
+            **recipient.try_borrow_mut_lamports()? += amount;
+            Ok(())
+        }
+        Some(payment_mint) => {
+            let vault = vault.ok_or(CustomError::InvalidPaymentToken)?;
+            let recipient_payment_ata = recipient_payment_ata.ok_or(CustomError::InvalidPaymentToken)?;
+            if vault.owner != holder.key() || vault.mint != payment_mint
+                || recipient_payment_ata.mint != payment_mint || recipient_payment_ata.owner != recipient.key() {
+                return err!(CustomError::InvalidPaymentToken);
+            }
+            anchor_spl::token::transfer(
+                CpiContext::new_with_signer(token_program.to_account_info(),
+                anchor_spl::token::Transfer {
+                    from: vault.to_account_info(),
+                    to: recipient_payment_ata.to_account_info(),
+                    authority: holder.clone(),
+                },
+                &[holder_seeds],
+                ),
+                amount
+            )
+        }
+    }
+}
+
+// Closes the (emptied) token account `vault` of a PDA (the `holder`, signing with `holder_seeds`)
+pub fn close_held_vault<'info>(
+    holder: &AccountInfo<'info>,
+    holder_seeds: &[&[u8]],
+    payment_mint: Pubkey,
+    vault: &Account<'info, TokenAccount>,
+    destination: &AccountInfo<'info>,
+    token_program: &Program<'info, Token>,
+) -> Result<()> {
+    if vault.owner != holder.key() || vault.mint != payment_mint {
+        return err!(CustomError::InvalidPaymentToken);
+    }
+    anchor_spl::token::close_account(
+        CpiContext::new_with_signer(token_program.to_account_info(),
+        anchor_spl::token::CloseAccount {
+            account: vault.to_account_info(),
+            destination: destination.clone(),
+            authority: holder.clone(),
+        },
+        &[holder_seeds],
+    ))
+}
+
+// Pays `amount` straight from `payer` to `recipient`, in $SOL or in SPL-tokens of `payment_mint`
+pub fn pay_directly<'info>(
+    payer: &Signer<'info>,
+    payment: Payment,
+    recipient: &AccountInfo<'info>,
+    payer_payment_ata: Option<&Account<'info, TokenAccount>>,
+    recipient_payment_ata: Option<&Account<'info, TokenAccount>>,
+    token_program: &Program<'info, Token>,
+    system_program: &Program<'info, System>,
+) -> Result<()> {
+    let Payment { mint: payment_mint, amount } = payment;
+    match payment_mint {
+        None => {
+            system_program::transfer(
+                CpiContext::new(system_program.to_account_info(),
+                system_program::Transfer {
+                    from: payer.to_account_info(),
+                    to: recipient.clone(),
+                },),
+                amount,
+            )
+        }
+        Some(payment_mint) => {
+            let payer_payment_ata = payer_payment_ata.ok_or(CustomError::InvalidPaymentToken)?;
+            let recipient_payment_ata = recipient_payment_ata.ok_or(CustomError::InvalidPaymentToken)?;
+            if payer_payment_ata.mint != payment_mint || recipient_payment_ata.mint != payment_mint || recipient_payment_ata.owner != recipient.key() {
+                return err!(CustomError::InvalidPaymentToken);
+            }
+            anchor_spl::token::transfer(
+                CpiContext::new(
+                    token_program.to_account_info(),
+                    anchor_spl::token::Transfer {
+                        from: payer_payment_ata.to_account_info(),
+                        to: recipient_payment_ata.to_account_info(),
+                        authority: payer.to_account_info(),
+                    },
+                ), amount)
+        }
+    }
+}
+
+// Sets the escrow's reward stake to its current amount, or to 0 once `unstake`d, and adds the rewards accrued by the
+// previous stake to `rewards_owed`. Must be called after every change of the amount of an escrow with a minted key.
+// Only keyed escrows are staked (keyless kinds never are) and escrows that existed before the pool join it on their next call.
+// Instructions closing an escrow unstake it first and then pay its `rewards_owed` with `pay_owed_rewards` to whoever
+// receives the assets (the holder on `retrieve`, the depositor or beneficiary otherwise).
+pub fn sync_rewards(
+    escrow: &mut Escrow,
+    mint_policy: Option<&Account<MintPolicy>>,
+    rewards_pool: Option<&mut Account<RewardsPool>>,
+    now: i64,
+    unstake: bool,
+) -> Result<()> {
+    let new_stake = if unstake || !escrow.nft_acquired { 0 } else { escrow.token_amount };
+    if escrow.is_solana || (escrow.reward_stake == 0 && new_stake == 0) {
+        return Ok(());
+    }
+    if !mint_policy.ok_or(CustomError::MissingMintPolicy)?.rewards_enabled {
+        return Ok(());
+    }
+    let rewards_pool = rewards_pool.ok_or(CustomError::MissingRewardsPool)?;
+    let rewards = rewards_pool.restake(escrow.reward_stake, escrow.reward_per_token_paid, new_stake, now)?;
+    escrow.rewards_owed = escrow.rewards_owed.saturating_add(rewards);
+    escrow.reward_stake = new_stake;
+    escrow.reward_per_token_paid = rewards_pool.acc_reward_per_token;
+    Ok(())
+}
+
+// Pays the rewards owed to an unstaked escrow that is being closed. An underfunded pool must not block the closure,
+// the shortfall is forfeited.
+pub fn pay_owed_rewards<'info>(
+    escrow: &Escrow,
+    rewards_pool: Option<&Account<'info, RewardsPool>>,
+    reward_vault: Option<&Account<'info, TokenAccount>>,
+    recipient_reward_ata: Option<&Account<'info, TokenAccount>>,
+    recipient: Pubkey,
+    token_program: &Program<'info, Token>,
+) -> Result<()> {
+    if escrow.rewards_owed == 0 {
+        return Ok(());
+    }
+    msg!("Paying out the accrued rewards.");
+    let rewards_pool = rewards_pool.ok_or(CustomError::MissingRewardsPool)?;
+    let reward_vault = reward_vault.ok_or(CustomError::MissingRewardsPool)?;
+    let recipient_reward_ata = recipient_reward_ata.ok_or(CustomError::InvalidUserToken)?;
+    let rewards = escrow.rewards_owed.min(reward_vault.amount);
+    pay_rewards(rewards_pool, rewards, reward_vault, recipient_reward_ata, recipient, token_program)
+}
+
+pub fn pay_rewards<'info>(
+    rewards_pool: &Account<'info, RewardsPool>,
+    amount: u64,
+    reward_vault: &Account<'info, TokenAccount>,
+    recipient_reward_ata: &Account<'info, TokenAccount>,
+    recipient: Pubkey,
+    token_program: &Program<'info, Token>,
+) -> Result<()> {
+    if reward_vault.key() != rewards_pool.reward_vault {
+        return err!(CustomError::MissingRewardsPool);
+    }
+    if recipient_reward_ata.mint != rewards_pool.reward_mint || recipient_reward_ata.owner != recipient {
+        return err!(CustomError::InvalidUserToken);
+    }
+    if amount == 0 {
+        return Ok(());
+    }
+    anchor_spl::token::transfer(
+        CpiContext::new_with_signer(token_program.to_account_info(),
+        anchor_spl::token::Transfer {
+            from: reward_vault.to_account_info(),
+            to: recipient_reward_ata.to_account_info(),
+            authority: rewards_pool.to_account_info(),
+        },
+        &[&[b"rewards_pool", rewards_pool.token_mint.as_ref(), &[rewards_pool.bump]]],
+        ),
+        amount
+    )
+}
+
+// Context (de)serialization structures
+
+#[derive(Accounts)]
+pub struct InitConfig<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    // PDA of seeds ["config"], there is only one per program
+    #[account(init, payer = admin, space = Config::LEN, seeds = [b"config"], bump)]
+    pub config: Account<'info, Config>,
+    // Only the upgrade authority of this program may create the config (and become its admin)
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+    pub program: Program<'info, crate::program::EscrowHeadonAttempt>,
+    #[account(constraint = program_data.upgrade_authority_address == Some(admin.key()) @ CustomError::Unauthorized)]
+    pub program_data: Account<'info, ProgramData>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    pub admin: Signer<'info>,
+    #[account(mut, seeds = [b"config"], bump = config.bump, has_one = admin @ CustomError::Unauthorized)]
+    pub config: Account<'info, Config>,
+}
+
+#[derive(Accounts)]
+pub struct InitCollection<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, has_one = admin @ CustomError::Unauthorized)]
+    pub config: Account<'info, Config>,
+    // PDA of seeds ["collection"], mint and freeze authority of the collection mint
+    #[account(init, payer = admin, space = KeyCollection::LEN, seeds = [b"collection"], bump)]
+    pub collection: Account<'info, KeyCollection>,
+    #[account(init, payer = admin, seeds = [b"collection_mint"], bump, mint::decimals = 0, mint::authority = collection, mint::freeze_authority = collection)]
+    pub collection_mint: Account<'info, Mint>,
+    #[account(init, payer = admin, associated_token::mint = collection_mint, associated_token::authority = collection,)]
+    pub collection_ata: Account<'info, TokenAccount>,
+    token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    rent: Sysvar<'info, Rent>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterMint<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, has_one = admin @ CustomError::Unauthorized)]
+    pub config: Account<'info, Config>,
+    pub token_mint: Account<'info, Mint>,
+    // PDA of seeds ["mint_policy", token_mint.PK], its existence is what allowlists the mint
+    #[account(init, payer = admin, space = MintPolicy::LEN, seeds = [b"mint_policy", token_mint.key().as_ref()], bump)]
+    pub mint_policy: Account<'info, MintPolicy>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateMintPolicy<'info> {
+    pub admin: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, has_one = admin @ CustomError::Unauthorized)]
+    pub config: Account<'info, Config>,
+    #[account(mut, seeds = [b"mint_policy", mint_policy.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Account<'info, MintPolicy>,
+}
+
+#[derive(Accounts)]
+pub struct InitRewardsPool<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, has_one = admin @ CustomError::Unauthorized)]
+    pub config: Account<'info, Config>,
+    #[account(mut, seeds = [b"mint_policy", mint_policy.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Account<'info, MintPolicy>,
+    // PDA of seeds ["rewards_pool", token_mint.PK], one pool per allowlisted mint
+    #[account(init, payer = admin, space = RewardsPool::LEN, seeds = [b"rewards_pool", mint_policy.token_mint.as_ref()], bump)]
+    pub rewards_pool: Account<'info, RewardsPool>,
+    pub reward_mint: Account<'info, Mint>,
+    #[account(init, payer = admin, associated_token::mint = reward_mint, associated_token::authority = rewards_pool,)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    rent: Sysvar<'info, Rent>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct FundRewardsPool<'info> {
+    pub admin: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, has_one = admin @ CustomError::Unauthorized)]
+    pub config: Account<'info, Config>,
+    #[account(seeds = [b"rewards_pool", rewards_pool.token_mint.as_ref()], bump = rewards_pool.bump)]
+    pub rewards_pool: Account<'info, RewardsPool>,
+    #[account(mut, address = rewards_pool.reward_vault)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub admin_reward_ata: Account<'info, TokenAccount>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct SetRewardRate<'info> {
+    pub admin: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, has_one = admin @ CustomError::Unauthorized)]
+    pub config: Account<'info, Config>,
+    #[account(mut, seeds = [b"rewards_pool", rewards_pool.token_mint.as_ref()], bump = rewards_pool.bump)]
+    pub rewards_pool: Account<'info, RewardsPool>,
+}
+
+#[derive(Accounts)]
+pub struct InitCounter<'info> {
+    #[account(mut)]
+    user: Signer<'info>,
+    // PDA (=Program Derived Address) of seeds ["counter", user.PK]
+    #[account(init, payer = user, space = UserEscrowCounter::LEN, seeds = [b"counter", user.key().as_ref()], bump)]
+    pub user_escrow_counter: Account<'info, UserEscrowCounter>,
+    system_program: Program<'info, System>,
+}
+
+// Not gated by the pause switch, the migration only makes existing escrows retrievable again
+#[derive(Accounts)]
+pub struct MigrateEscrow<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK: Verified in the instruction, an escrow in the legacy layout (which no longer deserializes as `Escrow`)
+    #[account(mut, owner = crate::ID)]
+    pub escrow: UncheckedAccount<'info>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeTokenEscrow<'info> {
+    #[account(mut)]
+    user: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = !config.paused @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    token_mint: Account<'info, Mint>,
+    // Only allowlisted mints can be deposited
+    #[account(mut, seeds = [b"mint_policy", token_mint.key().as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Account<'info, MintPolicy>,
+    // Mutable reference to the user's ATA (=Assosiated Token Account) (that already existed)
+    #[account(mut, constraint = user_token_ata.mint == token_mint.key() && user_token_ata.owner == user.key() || return err!(CustomError::InvalidUserToken))]
+    user_token_ata: Account<'info, TokenAccount>,
+    // Init NFT Mint account
+    #[account(init, payer = user, mint::decimals = 0, mint::authority = user, mint::freeze_authority = escrow)]
+    nft_mint: Account<'info, Mint>,
+    // Init Escrow Data account
+    #[account(init, payer = user, space = Escrow::LEN, seeds = ["escrow".as_bytes(), user.key().as_ref(), user_escrow_counter.counter.to_le_bytes().as_ref()], bump,)]
+    pub escrow: Account<'info, Escrow>,
+    // Mutable reference to the user's escrow counter
+    #[account(mut, seeds = [b"counter", user.key().as_ref()], bump = user_escrow_counter.bump)]
+    pub user_escrow_counter: Account<'info, UserEscrowCounter>,
+    // Newly created token account for storing the tokens, the authority is the escrow PDA
+    #[account(init, payer = user, token::mint = token_mint, token::authority = escrow,)]
+    escrow_token_ata: Account<'info, TokenAccount>,
+    // Additional Program/Sysvar that are needed
+    token_program: Program<'info, Token>,
+    rent: Sysvar<'info, Rent>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeSolEscrow<'info> {
+    #[account(mut)]
+    user: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = !config.paused @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    #[account(init, payer = user, mint::decimals = 0, mint::authority = user, mint::freeze_authority = escrow)]
+    nft_mint: Account<'info, Mint>,
+    #[account(init, payer = user, space = Escrow::LEN, seeds = ["escrow".as_bytes(), user.key().as_ref(), user_escrow_counter.counter.to_le_bytes().as_ref()], bump,)]
+    pub escrow: Account<'info, Escrow>,
+    #[account(mut, seeds = [b"counter", user.key().as_ref()], bump = user_escrow_counter.bump)]
+    pub user_escrow_counter: Account<'info, UserEscrowCounter>,
+    token_program: Program<'info, Token>,
+    rent: Sysvar<'info, Rent>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct GetNFT<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = !config.paused @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    // Omitted before the admin runs `init_collection`
+    #[account(mut, seeds = [b"collection"], bump = collection.bump)]
+    pub collection: Option<Account<'info, KeyCollection>>,
+    #[account(mut, constraint = nft_mint.key() == escrow.nft_mint)]
+    pub nft_mint: Account<'info, Mint>,
+    #[account(init, payer = user, space = TokenMetadata::LEN, seeds = [b"metadata", nft_mint.key().as_ref()], bump)]
+    pub metadata_account: Account<'info, TokenMetadata>,
+    #[account(mut, seeds = ["escrow".as_bytes(), escrow.authority.as_ref(), user_escrow_counter.previous_counter.to_le_bytes().as_ref()], bump = escrow.bump,)]
+    pub escrow: Account<'info, Escrow>,
+    #[account(mut, seeds = [b"counter", user.key().as_ref()], bump = user_escrow_counter.bump)]
+    pub user_escrow_counter: Account<'info, UserEscrowCounter>,
+    #[account(init, payer = user, associated_token::mint = nft_mint, associated_token::authority = user,)]
+    pub user_nft_ata: Account<'info, TokenAccount>,
+    // The following accounts are omitted for $SOL escrows, the key starts earning the mint's rewards
+    #[account(seeds = [b"mint_policy", escrow.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Option<Account<'info, MintPolicy>>,
+    // Required when the mint has a rewards pool
+    #[account(mut, seeds = [b"rewards_pool", escrow.token_mint.as_ref()], bump = rewards_pool.bump)]
+    pub rewards_pool: Option<Account<'info, RewardsPool>>,
+    system_program: Program<'info, System>,
+    token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
+}
+
+#[derive(Accounts)]
+pub struct GetNFTFor<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = !config.paused @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    // Omitted before the admin runs `init_collection`
+    #[account(mut, seeds = [b"collection"], bump = collection.bump)]
+    pub collection: Option<Account<'info, KeyCollection>>,
+    /// CHECK: Any wallet can receive the NFT
+    pub recipient: UncheckedAccount<'info>,
+    #[account(mut, constraint = nft_mint.key() == escrow.nft_mint)]
+    pub nft_mint: Account<'info, Mint>,
+    #[account(init, payer = user, space = TokenMetadata::LEN, seeds = [b"metadata", nft_mint.key().as_ref()], bump)]
+    pub metadata_account: Account<'info, TokenMetadata>,
+    #[account(mut, seeds = ["escrow".as_bytes(), escrow.authority.as_ref(), user_escrow_counter.previous_counter.to_le_bytes().as_ref()], bump = escrow.bump,)]
+    pub escrow: Account<'info, Escrow>,
+    #[account(mut, seeds = [b"counter", user.key().as_ref()], bump = user_escrow_counter.bump)]
+    pub user_escrow_counter: Account<'info, UserEscrowCounter>,
+    #[account(init, payer = user, associated_token::mint = nft_mint, associated_token::authority = recipient,)]
+    pub recipient_nft_ata: Account<'info, TokenAccount>,
+    // The following accounts are omitted for $SOL escrows, the key starts earning the mint's rewards
+    #[account(seeds = [b"mint_policy", escrow.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Option<Account<'info, MintPolicy>>,
+    // Required when the mint has a rewards pool
+    #[account(mut, seeds = [b"rewards_pool", escrow.token_mint.as_ref()], bump = rewards_pool.bump)]
+    pub rewards_pool: Option<Account<'info, RewardsPool>>,
+    system_program: Program<'info, System>,
+    token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
+}
+
+#[derive(Accounts)]
+pub struct SplitEscrow<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = !config.paused @ CustomError::ProgramPaused)]
+    pub config: Box<Account<'info, Config>>,
+    // Omitted before the admin runs `init_collection`
+    #[account(mut, seeds = [b"collection"], bump = collection.bump)]
+    pub collection: Option<Box<Account<'info, KeyCollection>>>,
+    // The split escrow is derived from the holder's counter, the holder becomes its authority
+    #[account(mut, seeds = [b"counter", user.key().as_ref()], bump = user_escrow_counter.bump)]
+    pub user_escrow_counter: Box<Account<'info, UserEscrowCounter>>,
+    #[account(mut, seeds = [b"escrow", escrow.authority.as_ref(), escrow.escrow_number.to_le_bytes().as_ref()], bump = escrow.bump,)]
+    pub escrow: Box<Account<'info, Escrow>>,
+    // The current NFT, burned
+    #[account(mut, close = user, seeds = [b"metadata", escrow.nft_mint.as_ref()], bump = metadata_account.bump)]
+    pub metadata_account: Box<Account<'info, TokenMetadata>>,
+    #[account(mut, address = escrow.nft_mint)]
+    pub nft_mint: Box<Account<'info, Mint>>,
+    #[account(mut, constraint = user_nft_ata.mint == escrow.nft_mint && user_nft_ata.owner == user.key() && user_nft_ata.amount == 1 @ CustomError::InvalidNftHolder)]
+    pub user_nft_ata: Box<Account<'info, TokenAccount>>,
+    // The fresh NFT of the original escrow
+    #[account(init, payer = user, mint::decimals = 0, mint::authority = user, mint::freeze_authority = escrow)]
+    pub new_nft_mint: Box<Account<'info, Mint>>,
+    #[account(init, payer = user, space = TokenMetadata::LEN, seeds = [b"metadata", new_nft_mint.key().as_ref()], bump)]
+    pub new_metadata_account: Box<Account<'info, TokenMetadata>>,
+    #[account(init, payer = user, associated_token::mint = new_nft_mint, associated_token::authority = user,)]
+    pub new_nft_ata: Box<Account<'info, TokenAccount>>,
+    // The split escrow and its NFT
+    #[account(init, payer = user, space = Escrow::LEN, seeds = [b"escrow", user.key().as_ref(), user_escrow_counter.counter.to_le_bytes().as_ref()], bump,)]
+    pub split_escrow: Box<Account<'info, Escrow>>,
+    #[account(init, payer = user, mint::decimals = 0, mint::authority = user, mint::freeze_authority = split_escrow)]
+    pub split_nft_mint: Box<Account<'info, Mint>>,
+    #[account(init, payer = user, space = TokenMetadata::LEN, seeds = [b"metadata", split_nft_mint.key().as_ref()], bump)]
+    pub split_metadata_account: Box<Account<'info, TokenMetadata>>,
+    #[account(init, payer = user, associated_token::mint = split_nft_mint, associated_token::authority = user,)]
+    pub split_nft_ata: Box<Account<'info, TokenAccount>>,
+    // The following accounts are omitted for $SOL escrows
+    #[account(address = escrow.token_mint)]
+    pub token_mint: Option<Box<Account<'info, Mint>>>,
+    #[account(mut, address = escrow.escrow_token_ata @ CustomError::InvalidEscrowToken)]
+    pub escrow_token_ata: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(init, payer = user, token::mint = token_mint, token::authority = split_escrow,)]
+    pub split_escrow_token_ata: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(seeds = [b"mint_policy", escrow.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Option<Box<Account<'info, MintPolicy>>>,
+    // Required when the mint has a rewards pool
+    #[account(mut, seeds = [b"rewards_pool", escrow.token_mint.as_ref()], bump = rewards_pool.bump)]
+    pub rewards_pool: Option<Box<Account<'info, RewardsPool>>>,
+    token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    rent: Sysvar<'info, Rent>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MergeEscrows<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = !config.paused @ CustomError::ProgramPaused)]
+    pub config: Box<Account<'info, Config>>,
+    // Omitted before the admin runs `init_collection`
+    #[account(mut, seeds = [b"collection"], bump = collection.bump)]
+    pub collection: Option<Box<Account<'info, KeyCollection>>>,
+    // The escrow that receives the funds and a fresh NFT
+    #[account(mut, seeds = [b"escrow", escrow.authority.as_ref(), escrow.escrow_number.to_le_bytes().as_ref()], bump = escrow.bump,)]
+    pub escrow: Box<Account<'info, Escrow>>,
+    #[account(mut, close = user, seeds = [b"metadata", escrow.nft_mint.as_ref()], bump = metadata_account.bump)]
+    pub metadata_account: Box<Account<'info, TokenMetadata>>,
+    #[account(mut, address = escrow.nft_mint)]
+    pub nft_mint: Box<Account<'info, Mint>>,
+    #[account(mut, constraint = user_nft_ata.mint == escrow.nft_mint && user_nft_ata.owner == user.key() && user_nft_ata.amount == 1 @ CustomError::InvalidNftHolder)]
+    pub user_nft_ata: Box<Account<'info, TokenAccount>>,
+    // The escrow that is emptied and closed
+    #[account(mut, close = user, seeds = [b"escrow", source_escrow.authority.as_ref(), source_escrow.escrow_number.to_le_bytes().as_ref()], bump = source_escrow.bump,
+        constraint = source_escrow.key() != escrow.key() @ CustomError::MergeNotSupported)]
+    pub source_escrow: Box<Account<'info, Escrow>>,
+    #[account(mut, close = user, seeds = [b"metadata", source_escrow.nft_mint.as_ref()], bump = source_metadata_account.bump)]
+    pub source_metadata_account: Box<Account<'info, TokenMetadata>>,
+    #[account(mut, address = source_escrow.nft_mint)]
+    pub source_nft_mint: Box<Account<'info, Mint>>,
+    #[account(mut, constraint = source_nft_ata.mint == source_escrow.nft_mint && source_nft_ata.owner == user.key() && source_nft_ata.amount == 1 @ CustomError::InvalidNftHolder)]
+    pub source_nft_ata: Box<Account<'info, TokenAccount>>,
+    // The fresh NFT of the merged escrow
+    #[account(init, payer = user, mint::decimals = 0, mint::authority = user, mint::freeze_authority = escrow)]
+    pub new_nft_mint: Box<Account<'info, Mint>>,
+    #[account(init, payer = user, space = TokenMetadata::LEN, seeds = [b"metadata", new_nft_mint.key().as_ref()], bump)]
+    pub new_metadata_account: Box<Account<'info, TokenMetadata>>,
+    #[account(init, payer = user, associated_token::mint = new_nft_mint, associated_token::authority = user,)]
+    pub new_nft_ata: Box<Account<'info, TokenAccount>>,
+    // The following accounts are omitted for $SOL escrows
+    #[account(mut, address = escrow.escrow_token_ata @ CustomError::InvalidEscrowToken)]
+    pub escrow_token_ata: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut, address = source_escrow.escrow_token_ata @ CustomError::InvalidEscrowToken)]
+    pub source_escrow_token_ata: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(seeds = [b"mint_policy", escrow.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Option<Box<Account<'info, MintPolicy>>>,
+    // Required when the mint has a rewards pool
+    #[account(mut, seeds = [b"rewards_pool", escrow.token_mint.as_ref()], bump = rewards_pool.bump)]
+    pub rewards_pool: Option<Box<Account<'info, RewardsPool>>>,
+    token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    rent: Sysvar<'info, Rent>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RotateKey<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    // Rotation only touches existing keys, so it follows the retrieval rules of the pause switch
+    #[account(seeds = [b"config"], bump = config.bump, constraint = config.retrieve_allowed() @ CustomError::ProgramPaused)]
+    pub config: Box<Account<'info, Config>>,
+    // Omitted before the admin runs `init_collection`
+    #[account(mut, seeds = [b"collection"], bump = collection.bump)]
+    pub collection: Option<Box<Account<'info, KeyCollection>>>,
+    #[account(mut, seeds = [b"escrow", escrow.authority.as_ref(), escrow.escrow_number.to_le_bytes().as_ref()], bump = escrow.bump,)]
+    pub escrow: Box<Account<'info, Escrow>>,
+    // The current NFT, burned
+    #[account(mut, close = user, seeds = [b"metadata", escrow.nft_mint.as_ref()], bump = metadata_account.bump)]
+    pub metadata_account: Box<Account<'info, TokenMetadata>>,
+    #[account(mut, address = escrow.nft_mint)]
+    pub nft_mint: Box<Account<'info, Mint>>,
+    #[account(mut, constraint = user_nft_ata.mint == escrow.nft_mint && user_nft_ata.owner == user.key() && user_nft_ata.amount == 1 @ CustomError::InvalidNftHolder)]
+    pub user_nft_ata: Box<Account<'info, TokenAccount>>,
+    // The fresh NFT
+    #[account(init, payer = user, mint::decimals = 0, mint::authority = user, mint::freeze_authority = escrow)]
+    pub new_nft_mint: Box<Account<'info, Mint>>,
+    #[account(init, payer = user, space = TokenMetadata::LEN, seeds = [b"metadata", new_nft_mint.key().as_ref()], bump)]
+    pub new_metadata_account: Box<Account<'info, TokenMetadata>>,
+    #[account(init, payer = user, associated_token::mint = new_nft_mint, associated_token::authority = user,)]
+    pub new_nft_ata: Box<Account<'info, TokenAccount>>,
+    token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    rent: Sysvar<'info, Rent>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ListKey<'info> {
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = !config.paused @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    #[account(constraint = escrow.nft_mint == nft_mint.key() && escrow.nft_acquired @ CustomError::InvalidNftHolder,
+        constraint = nft_mint.supply == 1 @ CustomError::InvalidKeySupply)]
+    pub escrow: Box<Account<'info, Escrow>>,
+    pub nft_mint: Account<'info, Mint>,
+    #[account(mut, constraint = seller_nft_ata.mint == nft_mint.key() && seller_nft_ata.owner == seller.key() && seller_nft_ata.amount == 1 @ CustomError::InvalidNftHolder)]
+    pub seller_nft_ata: Account<'info, TokenAccount>,
+    // There is at most one listing per NFT
+    #[account(init, payer = seller, space = Listing::LEN, seeds = [b"listing", nft_mint.key().as_ref()], bump)]
+    pub listing: Account<'info, Listing>,
+    #[account(init, payer = seller, associated_token::mint = nft_mint, associated_token::authority = listing,)]
+    pub listing_nft_ata: Account<'info, TokenAccount>,
+    token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    rent: Sysvar<'info, Rent>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateListing<'info> {
+    pub seller: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = !config.paused @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    #[account(mut, has_one = seller @ CustomError::Unauthorized, seeds = [b"listing", listing.nft_mint.as_ref()], bump = listing.bump)]
+    pub listing: Account<'info, Listing>,
+}
+
+// Not gated by the pause switch, sellers can always take their NFT back
+#[derive(Accounts)]
+pub struct CancelListing<'info> {
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    #[account(mut, close = seller, has_one = seller @ CustomError::Unauthorized, seeds = [b"listing", listing.nft_mint.as_ref()], bump = listing.bump)]
+    pub listing: Account<'info, Listing>,
+    #[account(address = listing.nft_mint)]
+    pub nft_mint: Account<'info, Mint>,
+    #[account(mut, associated_token::mint = nft_mint, associated_token::authority = listing,)]
+    pub listing_nft_ata: Account<'info, TokenAccount>,
+    #[account(mut, constraint = seller_nft_ata.mint == nft_mint.key() && seller_nft_ata.owner == seller.key() @ CustomError::InvalidNftHolder)]
+    pub seller_nft_ata: Account<'info, TokenAccount>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct BuyListing<'info> {
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = !config.paused @ CustomError::ProgramPaused)]
+    pub config: Box<Account<'info, Config>>,
+    /// CHECK: The seller of the listing, receives the payment and the listing's rent
+    #[account(mut, address = listing.seller)]
+    pub seller: UncheckedAccount<'info>,
+    #[account(mut, close = seller, seeds = [b"listing", listing.nft_mint.as_ref()], bump = listing.bump)]
+    pub listing: Box<Account<'info, Listing>>,
+    // Must still exist, keys of closed (refunded, expired, ...) escrows cannot be sold
+    #[account(address = listing.escrow)]
+    pub escrow: Box<Account<'info, Escrow>>,
+    #[account(address = listing.nft_mint)]
+    pub nft_mint: Box<Account<'info, Mint>>,
+    #[account(mut, associated_token::mint = nft_mint, associated_token::authority = listing,)]
+    pub listing_nft_ata: Box<Account<'info, TokenAccount>>,
+    // Created by the buyer beforehand (e.g. idempotently in the same transaction)
+    #[account(mut, constraint = buyer_nft_ata.mint == nft_mint.key() && buyer_nft_ata.owner == buyer.key() @ CustomError::InvalidUserToken)]
+    pub buyer_nft_ata: Box<Account<'info, TokenAccount>>,
+    // Required when the price is in an SPL-token, omitted for $SOL prices
+    #[account(mut)]
+    pub buyer_payment_ata: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub seller_payment_ata: Option<Box<Account<'info, TokenAccount>>>,
+    token_program: Program<'info, Token>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CreateAuction<'info> {
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = !config.paused @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    #[account(constraint = escrow.nft_mint == nft_mint.key() && escrow.nft_acquired @ CustomError::InvalidNftHolder,
+        constraint = nft_mint.supply == 1 @ CustomError::InvalidKeySupply)]
+    pub escrow: Box<Account<'info, Escrow>>,
+    pub nft_mint: Account<'info, Mint>,
+    #[account(mut, constraint = seller_nft_ata.mint == nft_mint.key() && seller_nft_ata.owner == seller.key() && seller_nft_ata.amount == 1 @ CustomError::InvalidNftHolder)]
+    pub seller_nft_ata: Account<'info, TokenAccount>,
+    // There is at most one auction per NFT
+    #[account(init, payer = seller, space = Auction::LEN, seeds = [b"auction", nft_mint.key().as_ref()], bump)]
+    pub auction: Account<'info, Auction>,
+    #[account(init, payer = seller, associated_token::mint = nft_mint, associated_token::authority = auction,)]
+    pub auction_nft_ata: Account<'info, TokenAccount>,
+    token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    rent: Sysvar<'info, Rent>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct PlaceBid<'info> {
+    #[account(mut)]
+    pub bidder: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = !config.paused @ CustomError::ProgramPaused)]
+    pub config: Box<Account<'info, Config>>,
+    #[account(mut, seeds = [b"auction", auction.nft_mint.as_ref()], bump = auction.bump)]
+    pub auction: Box<Account<'info, Auction>>,
+    // Must still exist, bids on keys of closed escrows are not accepted
+    #[account(address = auction.escrow)]
+    pub escrow: Box<Account<'info, Escrow>>,
+    /// CHECK: Verified against `auction.highest_bidder`, omitted for the first bid
+    #[account(mut)]
+    pub previous_bidder: Option<UncheckedAccount<'info>>,
+    // Required when bidding in an SPL-token, omitted for $SOL bids.
+    // The vault is the auction PDA's associated token account of the bid mint, created by the client.
+    #[account(mut)]
+    pub bidder_payment_ata: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut, constraint = auction.bid_mint.map(|mint| get_associated_token_address(&auction.key(), &mint)) == Some(bid_vault.key()) @ CustomError::InvalidPaymentToken)]
+    pub bid_vault: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub previous_bidder_payment_ata: Option<Box<Account<'info, TokenAccount>>>,
+    token_program: Program<'info, Token>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SettleAuction<'info> {
+    // Anyone can settle an ended auction
+    pub caller: Signer<'info>,
+    // Settlement only moves existing keys and bids, so it follows the retrieval rules of the pause switch
+    #[account(seeds = [b"config"], bump = config.bump, constraint = config.retrieve_allowed() @ CustomError::ProgramPaused)]
+    pub config: Box<Account<'info, Config>>,
+    /// CHECK: The seller of the auction, receives the rent of the closed accounts
+    #[account(mut, address = auction.seller)]
+    pub seller: UncheckedAccount<'info>,
+    #[account(mut, close = seller, seeds = [b"auction", auction.nft_mint.as_ref()], bump = auction.bump)]
+    pub auction: Box<Account<'info, Auction>>,
+    /// CHECK: The auctioned key's escrow, it may have been closed since the auction was created
+    #[account(address = auction.escrow)]
+    pub escrow: UncheckedAccount<'info>,
+    #[account(address = auction.nft_mint)]
+    pub nft_mint: Box<Account<'info, Mint>>,
+    #[account(mut, associated_token::mint = nft_mint, associated_token::authority = auction,)]
+    pub auction_nft_ata: Box<Account<'info, TokenAccount>>,
+    // The winner's (or, without a valid sale, the seller's) token account of the NFT, created by the client
+    #[account(mut, constraint = nft_destination_ata.mint == nft_mint.key() @ CustomError::InvalidUserToken)]
+    pub nft_destination_ata: Box<Account<'info, TokenAccount>>,
+    /// CHECK: Verified in the instruction, the seller (or the highest bidder without a valid sale), omitted when there were no bids
+    #[account(mut)]
+    pub bid_recipient: Option<UncheckedAccount<'info>>,
+    // Required when the bids are in an SPL-token, omitted for $SOL bids
+    #[account(mut, constraint = auction.bid_mint.map(|mint| get_associated_token_address(&auction.key(), &mint)) == Some(bid_vault.key()) @ CustomError::InvalidPaymentToken)]
+    pub bid_vault: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub bid_recipient_payment_ata: Option<Box<Account<'info, TokenAccount>>>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(offer_id: u64)]
+pub struct CreateLoanOffer<'info> {
+    #[account(mut)]
+    pub lender: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = !config.paused @ CustomError::ProgramPaused)]
+    pub config: Box<Account<'info, Config>>,
+    #[account(init, payer = lender, space = LoanOffer::LEN, seeds = [b"loan_offer", lender.key().as_ref(), offer_id.to_le_bytes().as_ref()], bump)]
+    pub offer: Box<Account<'info, LoanOffer>>,
+    // Required for loans in an SPL-token, omitted for $SOL loans.
+    // The vault is the offer PDA's associated token account of the loan mint, created by the client.
+    #[account(mut)]
+    pub lender_payment_ata: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub offer_vault: Option<Box<Account<'info, TokenAccount>>>,
+    token_program: Program<'info, Token>,
+    system_program: Program<'info, System>,
+}
+
+// Not gated by the pause switch, lenders can always withdraw their funds
+#[derive(Accounts)]
+pub struct CancelLoanOffer<'info> {
+    #[account(mut)]
+    pub lender: Signer<'info>,
+    #[account(mut, close = lender, has_one = lender @ CustomError::Unauthorized,
+        seeds = [b"loan_offer", offer.lender.as_ref(), offer.offer_id.to_le_bytes().as_ref()], bump = offer.bump)]
+    pub offer: Box<Account<'info, LoanOffer>>,
+    // Required for loans in an SPL-token, omitted for $SOL loans
+    #[account(mut)]
+    pub offer_vault: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub lender_payment_ata: Option<Box<Account<'info, TokenAccount>>>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct Borrow<'info> {
+    #[account(mut)]
+    pub borrower: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = !config.paused @ CustomError::ProgramPaused)]
+    pub config: Box<Account<'info, Config>>,
+    /// CHECK: The lender of the offer, receives the offer's rent
+    #[account(mut, address = offer.lender)]
+    pub lender: UncheckedAccount<'info>,
+    // Each offer funds a single loan
+    #[account(mut, close = lender, seeds = [b"loan_offer", offer.lender.as_ref(), offer.offer_id.to_le_bytes().as_ref()], bump = offer.bump)]
+    pub offer: Box<Account<'info, LoanOffer>>,
+    #[account(constraint = escrow.nft_mint == nft_mint.key() && escrow.nft_acquired @ CustomError::InvalidNftHolder,
+        constraint = nft_mint.supply == 1 @ CustomError::InvalidKeySupply)]
+    pub escrow: Box<Account<'info, Escrow>>,
+    #[account(seeds = [b"metadata", nft_mint.key().as_ref()], bump = metadata_account.bump)]
+    pub metadata_account: Box<Account<'info, TokenMetadata>>,
+    pub nft_mint: Box<Account<'info, Mint>>,
+    #[account(mut, constraint = borrower_nft_ata.mint == nft_mint.key() && borrower_nft_ata.owner == borrower.key() && borrower_nft_ata.amount == 1 @ CustomError::InvalidNftHolder)]
+    pub borrower_nft_ata: Box<Account<'info, TokenAccount>>,
+    #[account(init, payer = borrower, space = Loan::LEN, seeds = [b"loan", nft_mint.key().as_ref()], bump)]
+    pub loan: Box<Account<'info, Loan>>,
+    #[account(init, payer = borrower, associated_token::mint = nft_mint, associated_token::authority = loan,)]
+    pub loan_nft_ata: Box<Account<'info, TokenAccount>>,
+    // Required for loans in an SPL-token, omitted for $SOL loans
+    #[account(mut)]
+    pub offer_vault: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub borrower_payment_ata: Option<Box<Account<'info, TokenAccount>>>,
+    token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    rent: Sysvar<'info, Rent>,
+    system_program: Program<'info, System>,
+}
+
+// Repaying only moves existing keys and funds, so it follows the retrieval rules of the pause switch
+#[derive(Accounts)]
+pub struct RepayLoan<'info> {
+    #[account(mut)]
+    pub borrower: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = config.retrieve_allowed() @ CustomError::ProgramPaused)]
+    pub config: Box<Account<'info, Config>>,
+    /// CHECK: The lender of the loan, receives the repayment
+    #[account(mut, address = loan.lender)]
+    pub lender: UncheckedAccount<'info>,
+    #[account(mut, close = borrower, has_one = borrower @ CustomError::Unauthorized, seeds = [b"loan", loan.nft_mint.as_ref()], bump = loan.bump)]
+    pub loan: Box<Account<'info, Loan>>,
+    #[account(address = loan.nft_mint)]
+    pub nft_mint: Box<Account<'info, Mint>>,
+    #[account(mut, associated_token::mint = nft_mint, associated_token::authority = loan,)]
+    pub loan_nft_ata: Box<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = borrower_nft_ata.mint == nft_mint.key() && borrower_nft_ata.owner == borrower.key() @ CustomError::InvalidNftHolder)]
+    pub borrower_nft_ata: Box<Account<'info, TokenAccount>>,
+    // Required for loans in an SPL-token, omitted for $SOL loans
+    #[account(mut)]
+    pub borrower_payment_ata: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub lender_payment_ata: Option<Box<Account<'info, TokenAccount>>>,
+    token_program: Program<'info, Token>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimCollateral<'info> {
+    pub lender: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = config.retrieve_allowed() @ CustomError::ProgramPaused)]
+    pub config: Box<Account<'info, Config>>,
+    /// CHECK: The borrower of the loan, gets back the rent they paid for the loan accounts
+    #[account(mut, address = loan.borrower)]
+    pub borrower: UncheckedAccount<'info>,
+    #[account(mut, close = borrower, has_one = lender @ CustomError::Unauthorized, seeds = [b"loan", loan.nft_mint.as_ref()], bump = loan.bump)]
+    pub loan: Box<Account<'info, Loan>>,
+    #[account(address = loan.nft_mint)]
+    pub nft_mint: Box<Account<'info, Mint>>,
+    #[account(mut, associated_token::mint = nft_mint, associated_token::authority = loan,)]
+    pub loan_nft_ata: Box<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = lender_nft_ata.mint == nft_mint.key() && lender_nft_ata.owner == lender.key() @ CustomError::InvalidUserToken)]
+    pub lender_nft_ata: Box<Account<'info, TokenAccount>>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimRewards<'info> {
+    pub user: Signer<'info>,
+    // Claiming only concerns existing keys, so it follows the retrieval rules of the pause switch
+    #[account(seeds = [b"config"], bump = config.bump, constraint = config.retrieve_allowed() @ CustomError::ProgramPaused)]
+    pub config: Box<Account<'info, Config>>,
+    #[account(mut, seeds = [b"escrow", escrow.authority.as_ref(), escrow.escrow_number.to_le_bytes().as_ref()], bump = escrow.bump,)]
+    pub escrow: Box<Account<'info, Escrow>>,
+    #[account(address = escrow.nft_mint, constraint = nft_mint.supply == 1 @ CustomError::InvalidKeySupply)]
+    pub nft_mint: Box<Account<'info, Mint>>,
+    #[account(constraint = user_nft_ata.mint == escrow.nft_mint && user_nft_ata.owner == user.key() && user_nft_ata.amount == 1 @ CustomError::InvalidNftHolder)]
+    pub user_nft_ata: Box<Account<'info, TokenAccount>>,
+    #[account(seeds = [b"mint_policy", escrow.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Box<Account<'info, MintPolicy>>,
+    #[account(mut, seeds = [b"rewards_pool", escrow.token_mint.as_ref()], bump = rewards_pool.bump)]
+    pub rewards_pool: Box<Account<'info, RewardsPool>>,
+    #[account(mut, address = rewards_pool.reward_vault)]
+    pub reward_vault: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub user_reward_ata: Box<Account<'info, TokenAccount>>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct GetVotingPower<'info> {
+    pub escrow: Account<'info, Escrow>,
+    #[account(address = escrow.nft_mint, constraint = nft_mint.supply == 1 @ CustomError::InvalidKeySupply)]
+    pub nft_mint: Account<'info, Mint>,
+    // The voting power belongs to whoever holds the NFT
+    #[account(constraint = holder_nft_ata.mint == escrow.nft_mint && holder_nft_ata.amount == 1 && escrow.nft_acquired @ CustomError::InvalidNftHolder)]
+    pub holder_nft_ata: Account<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct GetEscrowInfo<'info> {
+    pub escrow: Account<'info, Escrow>,
+    // The NFT's token account, omitted when the holder is not needed (or the NFT was not minted yet)
+    pub holder_nft_ata: Option<Account<'info, TokenAccount>>,
+}
+
+#[derive(Accounts)]
+pub struct GetUserEscrowsSummary<'info> {
+    /// CHECK: Only used to derive the counter and to check the escrows' authority
+    pub user: UncheckedAccount<'info>,
+    #[account(seeds = [b"counter", user.key().as_ref()], bump = user_escrow_counter.bump)]
+    pub user_escrow_counter: Account<'info, UserEscrowCounter>,
+}
+
+// Used by the instructions that set an escrow up before its NFT is minted
+#[derive(Accounts)]
+pub struct ConfigureEscrow<'info> {
+    pub user: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = !config.paused @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    #[account(mut, constraint = escrow.authority == user.key() @ CustomError::Unauthorized, constraint = escrow.is_configurable() @ CustomError::EscrowLocked)]
+    pub escrow: Account<'info, Escrow>,
+}
+
+#[derive(Accounts)]
+pub struct Expire<'info> {
+    // Anyone can expire an escrow, the assets always go back to the depositor
+    pub caller: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = config.retrieve_allowed() @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    /// CHECK: The depositor receiving the assets, checked against the escrow
+    #[account(mut, address = escrow.authority)]
+    pub authority: UncheckedAccount<'info>,
+    #[account(mut, close = authority, seeds = [b"escrow", escrow.authority.as_ref(), escrow.escrow_number.to_le_bytes().as_ref()], bump = escrow.bump,)]
+    pub escrow: Account<'info, Escrow>,
+    // The following accounts are required once the NFT has been minted
+    #[account(mut, close = authority, seeds = [b"metadata", escrow.nft_mint.as_ref()], bump = metadata_account.bump)]
+    pub metadata_account: Option<Account<'info, TokenMetadata>>,
+    #[account(address = escrow.nft_mint)]
+    pub nft_mint: Option<Account<'info, Mint>>,
+    // Whichever token account currently holds the NFT, omitted when the NFT was burned
+    #[account(mut, constraint = holder_nft_ata.mint == escrow.nft_mint && holder_nft_ata.amount == 1 @ CustomError::InvalidNftHolder)]
+    pub holder_nft_ata: Option<Account<'info, TokenAccount>>,
+    // The following accounts are omitted for $SOL escrows
+    #[account(mut)]
+    pub escrow_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = authority_token_ata.owner == escrow.authority @ CustomError::InvalidUserToken)]
+    pub authority_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"mint_policy", escrow.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Option<Account<'info, MintPolicy>>,
+    // Required when the mint has a rewards pool
+    #[account(mut, seeds = [b"rewards_pool", escrow.token_mint.as_ref()], bump = rewards_pool.bump)]
+    pub rewards_pool: Option<Account<'info, RewardsPool>>,
+    // Required when the escrow's key earned rewards, they go to the depositor
+    #[account(mut)]
+    pub reward_vault: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub authority_reward_ata: Option<Box<Account<'info, TokenAccount>>>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct SendHeartbeat<'info> {
+    // Deliberately not subject to the pause switch, a missed heartbeat would hand the escrow over
+    pub user: Signer<'info>,
+    #[account(mut, constraint = escrow.authority == user.key() @ CustomError::Unauthorized)]
+    pub escrow: Account<'info, Escrow>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimInheritance<'info> {
+    #[account(mut)]
+    pub beneficiary: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = config.retrieve_allowed() @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    #[account(mut, close = beneficiary, seeds = [b"escrow", escrow.authority.as_ref(), escrow.escrow_number.to_le_bytes().as_ref()], bump = escrow.bump,)]
+    pub escrow: Account<'info, Escrow>,
+    // The following accounts are required once the NFT has been minted
+    #[account(mut, close = beneficiary, seeds = [b"metadata", escrow.nft_mint.as_ref()], bump = metadata_account.bump)]
+    pub metadata_account: Option<Account<'info, TokenMetadata>>,
+    #[account(address = escrow.nft_mint)]
+    pub nft_mint: Option<Account<'info, Mint>>,
+    // Whichever token account currently holds the NFT, omitted when the NFT was burned
+    #[account(mut, constraint = holder_nft_ata.mint == escrow.nft_mint && holder_nft_ata.amount == 1 @ CustomError::InvalidNftHolder)]
+    pub holder_nft_ata: Option<Account<'info, TokenAccount>>,
+    // The following accounts are omitted for $SOL escrows
+    #[account(mut)]
+    pub escrow_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = beneficiary_token_ata.owner == beneficiary.key() @ CustomError::InvalidUserToken)]
+    pub beneficiary_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"mint_policy", escrow.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Option<Account<'info, MintPolicy>>,
+    // Required when the mint has a rewards pool
+    #[account(mut, seeds = [b"rewards_pool", escrow.token_mint.as_ref()], bump = rewards_pool.bump)]
+    pub rewards_pool: Option<Account<'info, RewardsPool>>,
+    // Required when the escrow's key earned rewards, they go to the beneficiary
+    #[account(mut)]
+    pub reward_vault: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub beneficiary_reward_ata: Option<Box<Account<'info, TokenAccount>>>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimWithSecret<'info> {
+    #[account(mut)]
+    pub claimant: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = config.retrieve_allowed() @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    /// CHECK: The depositor, receives the rent of the closed accounts
+    #[account(mut, address = escrow.authority)]
+    pub authority: UncheckedAccount<'info>,
+    #[account(mut, close = authority, seeds = [b"escrow", escrow.authority.as_ref(), escrow.escrow_number.to_le_bytes().as_ref()], bump = escrow.bump,)]
+    pub escrow: Account<'info, Escrow>,
+    // The following accounts are omitted for $SOL escrows
+    #[account(mut)]
+    pub escrow_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = claimant_token_ata.owner == claimant.key() @ CustomError::InvalidUserToken)]
+    pub claimant_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"mint_policy", escrow.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Option<Account<'info, MintPolicy>>,
+    /// CHECK: The instructions sysvar, used to find the Ed25519 signature verification
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions: UncheckedAccount<'info>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct ReclaimSecretEscrow<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = config.retrieve_allowed() @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    #[account(mut, close = authority, has_one = authority @ CustomError::Unauthorized, seeds = [b"escrow", escrow.authority.as_ref(), escrow.escrow_number.to_le_bytes().as_ref()], bump = escrow.bump,)]
+    pub escrow: Account<'info, Escrow>,
+    // The following accounts are omitted for $SOL escrows
+    #[account(mut)]
+    pub escrow_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = authority_token_ata.owner == authority.key() @ CustomError::InvalidUserToken)]
+    pub authority_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"mint_policy", escrow.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Option<Account<'info, MintPolicy>>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct RedeemVoucher<'info> {
+    // Anyone can submit the voucher, usually the backend or the recipient
+    pub caller: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = config.retrieve_allowed() @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    #[account(mut, seeds = [b"escrow", escrow.authority.as_ref(), escrow.escrow_number.to_le_bytes().as_ref()], bump = escrow.bump,
+        constraint = !escrow.nft_acquired || authority_nft_ata.is_some() @ CustomError::InvalidNftHolder,
+        constraint = !escrow.nft_acquired || metadata_account.is_some() @ CustomError::MissingMetadata)]
+    pub escrow: Account<'info, Escrow>,
+    // Once the NFT is minted, vouchers are only valid while the depositor still holds it
+    #[account(constraint = authority_nft_ata.mint == escrow.nft_mint && authority_nft_ata.owner == escrow.authority && authority_nft_ata.amount == 1 @ CustomError::InvalidNftHolder)]
+    pub authority_nft_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"metadata", escrow.nft_mint.as_ref()], bump = metadata_account.bump)]
+    pub metadata_account: Option<Account<'info, TokenMetadata>>,
+    /// CHECK: Must match the recipient signed in the voucher
+    #[account(mut)]
+    pub recipient: UncheckedAccount<'info>,
+    // The following accounts are omitted for $SOL escrows
+    #[account(mut)]
+    pub escrow_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = recipient_token_ata.owner == recipient.key() @ CustomError::InvalidUserToken)]
+    pub recipient_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"mint_policy", escrow.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Option<Account<'info, MintPolicy>>,
+    // Required when the mint has a rewards pool
+    #[account(mut, seeds = [b"rewards_pool", escrow.token_mint.as_ref()], bump = rewards_pool.bump)]
+    pub rewards_pool: Option<Account<'info, RewardsPool>>,
+    /// CHECK: The instructions sysvar, used to find the Ed25519 signature verification
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions: UncheckedAccount<'info>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawStreamed<'info> {
+    #[account(mut)]
+    pub recipient: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = config.retrieve_allowed() @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    /// CHECK: The sender, receives the rent once the stream is fully paid out
+    #[account(mut, address = escrow.authority)]
+    pub authority: UncheckedAccount<'info>,
+    #[account(mut, seeds = [b"escrow", escrow.authority.as_ref(), escrow.escrow_number.to_le_bytes().as_ref()], bump = escrow.bump,)]
+    pub escrow: Account<'info, Escrow>,
+    // The following accounts are omitted for $SOL escrows
+    #[account(mut)]
+    pub escrow_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = recipient_token_ata.owner == recipient.key() @ CustomError::InvalidUserToken)]
+    pub recipient_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"mint_policy", escrow.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Option<Account<'info, MintPolicy>>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct CancelStream<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = config.retrieve_allowed() @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    /// CHECK: Checked against the recipient stored in the escrow
+    #[account(mut)]
+    pub recipient: UncheckedAccount<'info>,
+    #[account(mut, close = authority, has_one = authority @ CustomError::Unauthorized, seeds = [b"escrow", escrow.authority.as_ref(), escrow.escrow_number.to_le_bytes().as_ref()], bump = escrow.bump,)]
+    pub escrow: Account<'info, Escrow>,
+    // The following accounts are omitted for $SOL escrows
+    #[account(mut)]
+    pub escrow_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = recipient_token_ata.owner == recipient.key() @ CustomError::InvalidUserToken)]
+    pub recipient_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = authority_token_ata.owner == authority.key() @ CustomError::InvalidUserToken)]
+    pub authority_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"mint_policy", escrow.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Option<Account<'info, MintPolicy>>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterRecurringDeposit<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = !config.paused @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    #[account(seeds = [b"escrow", escrow.authority.as_ref(), escrow.escrow_number.to_le_bytes().as_ref()], bump = escrow.bump,
+        constraint = escrow.authority == user.key() @ CustomError::Unauthorized)]
+    pub escrow: Account<'info, Escrow>,
+    // PDA of seeds ["plan", escrow.PK], one recurring plan per escrow
+    #[account(init, payer = user, space = RecurringDeposit::LEN, seeds = [b"plan", escrow.key().as_ref()], bump)]
+    pub plan: Account<'info, RecurringDeposit>,
+    // Note: a token account has a single delegate, registering another plan from it replaces the allowance
+    #[account(mut, constraint = source_token_ata.mint == escrow.token_mint && source_token_ata.owner == user.key() @ CustomError::InvalidUserToken)]
+    pub source_token_ata: Account<'info, TokenAccount>,
+    token_program: Program<'info, Token>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CrankDeposit<'info> {
+    // Anyone can crank a due instalment
+    pub caller: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = !config.paused @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    #[account(mut, seeds = [b"escrow", escrow.authority.as_ref(), escrow.escrow_number.to_le_bytes().as_ref()], bump = escrow.bump,
+        constraint = !escrow.nft_acquired || metadata_account.is_some() @ CustomError::MissingMetadata)]
+    pub escrow: Account<'info, Escrow>,
+    #[account(mut, seeds = [b"plan", escrow.key().as_ref()], bump = plan.bump, has_one = escrow, has_one = source_token_ata)]
+    pub plan: Account<'info, RecurringDeposit>,
+    #[account(mut)]
+    pub source_token_ata: Account<'info, TokenAccount>,
+    #[account(mut, address = escrow.escrow_token_ata @ CustomError::InvalidEscrowToken)]
+    pub escrow_token_ata: Account<'info, TokenAccount>,
+    // Kept in sync with the escrow once the NFT has been minted
+    #[account(mut, seeds = [b"metadata", escrow.nft_mint.as_ref()], bump = metadata_account.bump)]
+    pub metadata_account: Option<Account<'info, TokenMetadata>>,
+    #[account(mut, seeds = [b"mint_policy", escrow.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Account<'info, MintPolicy>,
+    // Required when the mint has a rewards pool
+    #[account(mut, seeds = [b"rewards_pool", escrow.token_mint.as_ref()], bump = rewards_pool.bump)]
+    pub rewards_pool: Option<Account<'info, RewardsPool>>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct CancelRecurringDeposit<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    // The escrow itself is not needed, so plans of already retrieved escrows can be cancelled too
+    #[account(mut, close = user, seeds = [b"plan", plan.escrow.as_ref()], bump = plan.bump,
+        constraint = plan.owner == user.key() @ CustomError::Unauthorized, has_one = source_token_ata)]
+    pub plan: Account<'info, RecurringDeposit>,
+    #[account(mut)]
+    pub source_token_ata: Account<'info, TokenAccount>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct ApproveRelease<'info> {
+    pub arbiter: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = !config.paused @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    #[account(mut, constraint = escrow.arbiter == Some(arbiter.key()) @ CustomError::Unauthorized)]
+    pub escrow: Account<'info, Escrow>,
+}
+
+#[derive(Accounts)]
+pub struct Refund<'info> {
+    pub arbiter: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = config.retrieve_allowed() @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    /// CHECK: The depositor receiving the refund, checked against the escrow
+    #[account(mut, address = escrow.authority)]
+    pub authority: UncheckedAccount<'info>,
+    #[account(mut, close = authority, seeds = [b"escrow", escrow.authority.as_ref(), escrow.escrow_number.to_le_bytes().as_ref()], bump = escrow.bump,
+        constraint = escrow.arbiter == Some(arbiter.key()) @ CustomError::Unauthorized)]
+    pub escrow: Account<'info, Escrow>,
+    // The following accounts are required once the NFT has been minted
+    #[account(mut, close = authority, seeds = [b"metadata", escrow.nft_mint.as_ref()], bump = metadata_account.bump)]
+    pub metadata_account: Option<Account<'info, TokenMetadata>>,
+    #[account(address = escrow.nft_mint)]
+    pub nft_mint: Option<Account<'info, Mint>>,
+    // Whichever token account currently holds the NFT, omitted when the NFT was burned
+    #[account(mut, constraint = holder_nft_ata.mint == escrow.nft_mint && holder_nft_ata.amount == 1 @ CustomError::InvalidNftHolder)]
+    pub holder_nft_ata: Option<Account<'info, TokenAccount>>,
+    // The following accounts are omitted for $SOL escrows
+    #[account(mut)]
+    pub escrow_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = authority_token_ata.owner == escrow.authority @ CustomError::InvalidUserToken)]
+    pub authority_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"mint_policy", escrow.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Option<Account<'info, MintPolicy>>,
+    // Required when the mint has a rewards pool
+    #[account(mut, seeds = [b"rewards_pool", escrow.token_mint.as_ref()], bump = rewards_pool.bump)]
+    pub rewards_pool: Option<Account<'info, RewardsPool>>,
+    // Required when the escrow's key earned rewards, they go to the depositor
+    #[account(mut)]
+    pub reward_vault: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub authority_reward_ata: Option<Box<Account<'info, TokenAccount>>>,
+    token_program: Program<'info, Token>,
+}
+
+// Used by the milestone transitions that do not move any assets
+#[derive(Accounts)]
+pub struct MilestoneAction<'info> {
+    pub user: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = !config.paused @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub escrow: Account<'info, Escrow>,
+}
+
+#[derive(Accounts)]
+pub struct SettleMilestone<'info> {
+    pub user: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = config.retrieve_allowed() @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    /// CHECK: The payer (depositor), receives the payer's share and the closed accounts
+    #[account(mut, address = escrow.authority)]
+    pub authority: UncheckedAccount<'info>,
+    /// CHECK: Checked against the payee stored in the escrow
+    #[account(mut)]
+    pub payee: UncheckedAccount<'info>,
+    #[account(mut, seeds = [b"escrow", escrow.authority.as_ref(), escrow.escrow_number.to_le_bytes().as_ref()], bump = escrow.bump,)]
+    pub escrow: Account<'info, Escrow>,
+    // The following accounts are omitted for $SOL escrows
+    #[account(mut)]
+    pub escrow_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = payee_token_ata.owner == payee.key() @ CustomError::InvalidUserToken)]
+    pub payee_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = authority_token_ata.owner == escrow.authority @ CustomError::InvalidUserToken)]
+    pub authority_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"mint_policy", escrow.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Option<Account<'info, MintPolicy>>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimVested<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = config.retrieve_allowed() @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    #[account(mut, seeds = [b"metadata", escrow.nft_mint.as_ref()], bump = metadata_account.bump)]
+    pub metadata_account: Account<'info, TokenMetadata>,
+    #[account(mut, seeds = [b"escrow", escrow.authority.as_ref(), metadata_account.escrow_number.to_le_bytes().as_ref()], bump = escrow.bump,)]
+    pub escrow: Account<'info, Escrow>,
+    #[account(address = escrow.nft_mint, constraint = nft_mint.supply == 1 @ CustomError::InvalidKeySupply)]
+    pub nft_mint: Account<'info, Mint>,
+    // Holding the NFT is what entitles the user to the vested tokens
+    #[account(constraint = user_nft_ata.mint == escrow.nft_mint && user_nft_ata.owner == user.key() && user_nft_ata.amount == 1 @ CustomError::InvalidNftHolder)]
+    pub user_nft_ata: Account<'info, TokenAccount>,
+    // The following accounts are omitted for $SOL escrows
+    #[account(mut)]
+    pub escrow_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub user_token_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"mint_policy", escrow.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Option<Account<'info, MintPolicy>>,
+    // Required when the mint has a rewards pool
+    #[account(mut, seeds = [b"rewards_pool", escrow.token_mint.as_ref()], bump = rewards_pool.bump)]
+    pub rewards_pool: Option<Account<'info, RewardsPool>>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct Retrieve<'info> {
+    pub user: Signer<'info>,
+    /// CHECK: Owner of the NFT's token account, receives the assets (same as user unless user is a delegate)
+    #[account(mut, address = user_nft_ata.owner)]
+    pub holder: UncheckedAccount<'info>,
+    #[account(seeds = [b"config"], bump = config.bump, constraint = config.retrieve_allowed() @ CustomError::ProgramPaused)]
+    pub config: Account<'info, Config>,
+    #[account(mut, constraint = nft_mint.key() == escrow.nft_mint, constraint = nft_mint.supply == 1 @ CustomError::InvalidKeySupply)]
+    pub nft_mint: Account<'info, Mint>, 
+    #[account(mut, close = holder, seeds= [b"metadata", nft_mint.key().as_ref()], bump = metadata_account.bump)]
+    pub metadata_account: Account<'info, TokenMetadata>,
+    #[account(mut, close = holder, seeds = [b"escrow", escrow.authority.as_ref(), metadata_account.escrow_number.to_le_bytes().as_ref()], bump = escrow.bump,)]
+    pub escrow: Account<'info, Escrow>,
+    #[account(mut, constraint = user_nft_ata.mint == escrow.nft_mint && user_nft_ata.amount == 1 @ CustomError::InvalidNftHolder,
+        constraint = user_nft_ata.owner == user.key() || (user_nft_ata.delegate == COption::Some(user.key()) && user_nft_ata.delegated_amount >= 1) @ CustomError::InvalidNftHolder)]
+    pub user_nft_ata: Account<'info, TokenAccount>,
+    #[account(mut, constraint = escrow_token_ata.key() == user_nft_ata.key() || escrow_token_ata.key() == escrow.escrow_token_ata)]
+    pub escrow_token_ata: Account <'info, TokenAccount>,
+    #[account(mut, constraint = user_token_ata.key() == user_nft_ata.key() || (user_token_ata.mint == escrow.token_mint && user_token_ata.owner == holder.key()) @ CustomError::InvalidUserToken)]
+    pub user_token_ata: Account<'info, TokenAccount>,
+    // Required for SPL-token escrows of a registered mint (TVL tracking), omitted for $SOL escrows and unregistered mints
+    #[account(mut, seeds = [b"mint_policy", escrow.token_mint.as_ref()], bump = mint_policy.bump)]
+    pub mint_policy: Option<Account<'info, MintPolicy>>,
+    // Required when the mint has a rewards pool, the accrued rewards go to the holder
+    #[account(mut, seeds = [b"rewards_pool", escrow.token_mint.as_ref()], bump = rewards_pool.bump)]
+    pub rewards_pool: Option<Box<Account<'info, RewardsPool>>>,
+    #[account(mut)]
+    pub reward_vault: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub holder_reward_ata: Option<Box<Account<'info, TokenAccount>>>,
+    token_program: Program<'info, Token>,
+    system_program: Program<'info, System>,
+}
+
+// Data structures
+
+#[account]
+pub struct Escrow {
+    authority: Pubkey,
+    bump: u8,
+    token_mint: Pubkey,
+    escrow_token_ata: Pubkey,
+    token_amount: u64,
+    nft_mint: Pubkey,
+    nft_acquired: bool,
+    is_solana: bool,
+    kind: EscrowKind,
+    escrow_number: u64,
+    arbiter: Option<Pubkey>,
+    release_approved: bool,
+    signers: Vec<Pubkey>,
+    threshold: u8,
+    expires_at: Option<i64>,
+    voucher_nonce: u64,
+    soulbound: bool,
+    reward_per_token_paid: u128,
+    reward_stake: u64,
+    rewards_owed: u64,
+}
+
+// The escrow layout of the program's first version, converted by `migrate_escrow`
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct LegacyEscrow {
+    authority: Pubkey,
+    bump: u8,
+    token_mint: Pubkey,
+    escrow_token_ata: Pubkey,
+    token_amount: u64,
     nft_mint: Pubkey,
     nft_acquired: bool,
     is_solana: bool,
 }
 
+// The message the depositor signs off-chain (Borsh serialized) to authorize a `redeem_voucher`
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct Voucher {
+    pub escrow: Pubkey,
+    pub recipient: Pubkey,
+    pub amount: u64,
+    pub nonce: u64,
+    pub expiry: i64,
+}
+
+// The message signed (Borsh serialized) with the keypair derived from the secret of a hash-locked escrow
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct SecretClaim {
+    pub escrow: Pubkey,
+    pub claimant: Pubkey,
+}
+
+// Return data of `get_voting_power` (Borsh serialized)
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct VotingPower {
+    pub escrow: Pubkey,
+    pub holder: Pubkey,
+    pub voting_power: u128,
+    // 0 for escrows that are not time-locked
+    pub lock_end_ts: i64,
+}
+
+// Return data of `get_escrow_info` (Borsh serialized)
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct EscrowInfo {
+    pub escrow: Pubkey,
+    pub authority: Pubkey,
+    pub escrow_number: u64,
+    pub status: EscrowStatus,
+    pub kind: EscrowKind,
+    // None for $SOL escrows
+    pub token_mint: Option<Pubkey>,
+    pub token_amount: u64,
+    // None until the NFT is minted
+    pub nft_mint: Option<Pubkey>,
+    // None unless the NFT's token account was provided
+    pub holder: Option<Pubkey>,
+    pub expires_at: Option<i64>,
+    pub soulbound: bool,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum EscrowStatus {
+    // Deposited, the NFT was not minted yet
+    AwaitingKey,
+    // The NFT was minted, its holder can retrieve the assets (subject to the escrow's conditions)
+    KeyIssued,
+    // Hash-locked, streamed or milestone escrows, which are settled without an NFT
+    Keyless,
+    // Past `expires_at`, waiting for `expire` to return the assets to the depositor
+    Expired,
+}
+
+// Return data of `get_user_escrows_summary` (Borsh serialized)
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct UserEscrowsSummary {
+    pub user: Pubkey,
+    pub escrows_created: u64,
+    pub open_escrows: u32,
+    pub keys_issued: u32,
+    pub sol_escrows: u32,
+    pub lamports_escrowed: u64,
+    // Amounts of different mints are not comparable, so only the escrows are counted
+    pub token_escrows: u32,
+}
+
+pub const MAX_SIGNERS: usize = 5;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum EscrowKind {
+    // The whole amount can be retrieved at any time by the NFT holder
+    Standard,
+    // The NFT holder can claim the linearly vested part of the amount, `retrieve` only after `end_ts`
+    Vesting { start_ts: i64, cliff_ts: i64, end_ts: i64, released_amount: u64 },
+    // The depositor keeps the NFT, `beneficiary` can claim the amount once a heartbeat is missed
+    DeadManSwitch { beneficiary: Pubkey, heartbeat_interval: i64, last_heartbeat: i64 },
+    // Whoever holds the secret key of `claim_key` can claim the amount, there is no NFT
+    HashLock { claim_key: Pubkey, reclaim_after: i64 },
+    // The amount flows linearly to `recipient` between `start_ts` and `end_ts` (`rate_per_second` is for display), there is no NFT
+    Stream { recipient: Pubkey, start_ts: i64, end_ts: i64, rate_per_second: u64, withdrawn_amount: u64 },
+    // The amount is paid out to `payee` milestone by milestone, there is no NFT
+    Milestone { payee: Pubkey, milestones: Vec<Milestone> },
+}
+
+pub const MAX_MILESTONES: usize = 8;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub struct Milestone {
+    pub amount: u64,
+    pub status: MilestoneStatus,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum MilestoneStatus {
+    Pending,
+    Submitted,
+    Released,
+    Disputed,
+    Resolved,
+}
+
 #[account]
 pub struct TokenMetadata {
     pub token_title: String,
@@ -316,6 +3825,138 @@ pub struct TokenMetadata {
     pub escrowed_token_mint: Pubkey,
     pub escrowed_amount: u64,
     pub bump: u8,
+    pub recipient: Pubkey,
+    // The default pubkey for keys minted before the collection was created
+    pub collection: Pubkey,
+}
+
+#[account]
+pub struct KeyCollection {
+    pub collection_mint: Pubkey,
+    // Number of keys ever minted, burned keys are not subtracted
+    pub minted: u64,
+    pub bump: u8,
+}
+
+#[account]
+pub struct Config {
+    pub admin: Pubkey,
+    pub paused: bool,
+    pub allow_retrieve_while_paused: bool,
+    pub bump: u8,
+}
+
+#[account]
+pub struct MintPolicy {
+    pub token_mint: Pubkey,
+    pub enabled: bool,
+    pub min_deposit: u64,
+    pub max_per_escrow: u64,
+    pub tvl_cap: u64,
+    pub total_deposited: u64,
+    pub bump: u8,
+    pub rewards_enabled: bool,
+}
+
+// Emits `reward_rate` reward tokens per second, shared by the keyed escrows of `token_mint` in proportion to their amount.
+// `total_staked` is the sum of the escrows' `reward_stake`, `acc_reward_per_token` (scaled by `REWARD_PRECISION`) is the
+// reward accrued per staked token since the pool's creation, it is brought up to date whenever a stake changes.
+#[account]
+pub struct RewardsPool {
+    pub token_mint: Pubkey,
+    pub reward_mint: Pubkey,
+    pub reward_vault: Pubkey,
+    pub reward_rate: u64,
+    pub acc_reward_per_token: u128,
+    pub last_update_ts: i64,
+    pub bump: u8,
+    pub total_staked: u64,
+}
+
+pub const REWARD_PRECISION: u128 = 1_000_000_000_000;
+
+// One year, the longest interval between instalments of a recurring deposit
+pub const MAX_RECURRING_INTERVAL: i64 = 366 * 24 * 60 * 60;
+
+#[account]
+pub struct RecurringDeposit {
+    pub escrow: Pubkey,
+    pub owner: Pubkey,
+    pub source_token_ata: Pubkey,
+    pub amount: u64,
+    pub interval: i64,
+    pub next_deposit_at: i64,
+    pub instalments_remaining: u64,
+    pub bump: u8,
+}
+
+// An NFT offered for sale, the NFT itself sits in the listing PDA's token account
+#[account]
+pub struct Listing {
+    pub seller: Pubkey,
+    pub escrow: Pubkey,
+    pub nft_mint: Pubkey,
+    // None for prices in $SOL
+    pub price_mint: Option<Pubkey>,
+    pub price: u64,
+    pub bump: u8,
+}
+
+// An English auction of an NFT, the NFT sits in the auction PDA's token account until settlement
+// and the highest bid in the auction PDA itself ($SOL) or in its token account (SPL-token)
+#[account]
+pub struct Auction {
+    pub seller: Pubkey,
+    pub escrow: Pubkey,
+    pub nft_mint: Pubkey,
+    // None for bids in $SOL
+    pub bid_mint: Option<Pubkey>,
+    pub reserve_price: u64,
+    pub end_ts: i64,
+    pub highest_bidder: Option<Pubkey>,
+    pub highest_bid: u64,
+    pub bump: u8,
+}
+
+// A lender's standing offer, the loan sits in the offer PDA itself ($SOL) or in its token account (SPL-token)
+#[account]
+pub struct LoanOffer {
+    pub lender: Pubkey,
+    pub offer_id: u64,
+    // None for loans in $SOL
+    pub loan_mint: Option<Pubkey>,
+    pub principal: u64,
+    pub repayment: u64,
+    pub duration: i64,
+    // Accepted collateral: keys of escrows of this asset (`Pubkey::default()` for $SOL) holding at least `min_escrowed_amount`
+    pub collateral_token_mint: Pubkey,
+    pub min_escrowed_amount: u64,
+    pub bump: u8,
+}
+
+// The terms a lender offers in `create_loan_offer`, see `LoanOffer`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct LoanTerms {
+    pub loan_mint: Option<Pubkey>,
+    pub principal: u64,
+    pub repayment: u64,
+    pub duration: i64,
+    pub collateral_token_mint: Pubkey,
+    pub min_escrowed_amount: u64,
+}
+
+// A running loan, the NFT sits in the loan PDA's token account until repayment or default
+#[account]
+pub struct Loan {
+    pub borrower: Pubkey,
+    pub lender: Pubkey,
+    pub escrow: Pubkey,
+    pub nft_mint: Pubkey,
+    pub loan_mint: Option<Pubkey>,
+    pub principal: u64,
+    pub repayment: u64,
+    pub due_ts: i64,
+    pub bump: u8,
 }
 
 #[account]
@@ -327,19 +3968,161 @@ pub struct UserEscrowCounter {
 }
 
 
-// Data structures sizing
+// Data structures sizing
+
+impl Escrow {
+    pub const LEN: usize =
+    8       // discriminator
+    + 32    // authority: Pubkey
+    + 1     // bump: u8
+    + 32    // token_mint: Pubkey
+    + 32    // escrowed_tokens_token_account: Pubkey
+    + 8     // token_amount: u64
+    + 32    // nft_mint: Pubkey
+    + 1     // nft_acquired: bool
+    + 1     // is_solana: bool
+    + EscrowKind::LEN // kind: EscrowKind
+    + 8     // escrow_number: u64
+    + 1 + 32 // arbiter: Option<Pubkey>
+    + 1     // release_approved: bool
+    + 4 + MAX_SIGNERS * 32 // signers: Vec<Pubkey>
+    + 1     // threshold: u8
+    + 1 + 8 // expires_at: Option<i64>
+    + 8     // voucher_nonce: u64
+    + 1     // soulbound: bool
+    + 16    // reward_per_token_paid: u128
+    + 8     // reward_stake: u64
+    + 8;    // rewards_owed: u64
+}
+
+impl LegacyEscrow {
+    pub const LEN: usize =
+    8       // discriminator
+    + 32    // authority: Pubkey
+    + 1     // bump: u8
+    + 32    // token_mint: Pubkey
+    + 32    // escrowed_tokens_token_account: Pubkey
+    + 8     // token_amount: u64
+    + 32    // nft_mint: Pubkey
+    + 1     // nft_acquired: bool
+    + 1;    // is_solana: bool
+}
+
+impl EscrowKind {
+    pub const LEN: usize =
+    1       // variant
+    + 32    // payee: Pubkey
+    + 4 + MAX_MILESTONES * (8 + 1); // milestones: Vec<Milestone> (Milestone is the largest variant)
+
+    // Escrows of some kinds are not represented by an NFT
+    pub fn has_key(&self) -> bool {
+        !matches!(self, EscrowKind::Milestone { .. } | EscrowKind::HashLock { .. } | EscrowKind::Stream { .. })
+    }
+
+    pub fn milestone_mut(&mut self, index: u8) -> Result<(Pubkey, &mut Milestone)> {
+        match self {
+            EscrowKind::Milestone { payee, milestones } => {
+                let milestone = milestones.get_mut(index as usize).ok_or(CustomError::InvalidMilestones)?;
+                Ok((*payee, milestone))
+            }
+            _ => err!(CustomError::NotMilestoneEscrow),
+        }
+    }
+
+    pub fn milestones_settled(&self) -> bool {
+        match self {
+            EscrowKind::Milestone { milestones, .. } => milestones.iter().all(|milestone| {
+                milestone.status == MilestoneStatus::Released || milestone.status == MilestoneStatus::Resolved
+            }),
+            _ => false,
+        }
+    }
+}
+
+impl Escrow {
+    pub fn check_key_mintable(&self) -> Result<()> {
+        assert!(self.nft_acquired == false, "NFT already acquired!");
+        if !self.kind.has_key() {
+            return err!(CustomError::NoKeyForEscrowKind);
+        }
+        Ok(())
+    }
+
+    // Restrictions in favour of the depositor (arbiter refund, expiry) would be lost by moving the funds
+    // to another escrow, so only plain escrows can be split or merged
+    pub fn is_plain(&self) -> bool {
+        self.kind == EscrowKind::Standard && self.arbiter.is_none() && self.expires_at.is_none()
+    }
+
+    // Escrows can be set up by the depositor until they are handed over (the NFT is minted or the escrow got a keyless kind)
+    pub fn is_configurable(&self) -> bool {
+        !self.nft_acquired && self.kind.has_key()
+    }
+
+    // Only vesting escrows are time-locked (`retrieve` waits for `end_ts`): amount times the seconds left, 0 otherwise
+    pub fn voting_power(&self, now: i64) -> u128 {
+        match self.kind {
+            EscrowKind::Vesting { end_ts, .. } if now < end_ts && !self.is_expired(now) => self.token_amount as u128 * (end_ts - now) as u128,
+            _ => 0,
+        }
+    }
+
+    pub fn status(&self, now: i64) -> EscrowStatus {
+        if self.is_expired(now) {
+            EscrowStatus::Expired
+        } else if !self.kind.has_key() {
+            EscrowStatus::Keyless
+        } else if self.nft_acquired {
+            EscrowStatus::KeyIssued
+        } else {
+            EscrowStatus::AwaitingKey
+        }
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if now >= expires_at)
+    }
+
+    // Conditions shared by every withdrawal made by the NFT holder
+    pub fn check_release(&self, remaining_accounts: &[AccountInfo], now: i64) -> Result<()> {
+        if self.is_expired(now) {
+            return err!(CustomError::EscrowExpired);
+        }
+        if self.arbiter.is_some() && !self.release_approved {
+            return err!(CustomError::ReleaseNotApproved);
+        }
+        self.check_retrieval_signers(remaining_accounts)
+    }
 
-impl Escrow {
-    pub const LEN: usize =
-    8       // discriminator
-    + 32    // authority: Pubkey
-    + 1     // bump: u8
-    + 32    // token_mint: Pubkey
-    + 32    // escrowed_tokens_token_account: Pubkey
-    + 8     // token_amount: u64
-    + 32    // nft_mint: Pubkey
-    + 1     // nft_acquired: bool
-    + 1;    // is_solana: bool
+    // An M-of-N set of distinct signers, with 1 <= M <= N <= MAX_SIGNERS
+    pub fn check_signer_set(signers: &[Pubkey], threshold: u8) -> Result<()> {
+        if threshold == 0 || threshold as usize > signers.len() || signers.len() > MAX_SIGNERS {
+            return err!(CustomError::InvalidSignerSet);
+        }
+        for (i, signer) in signers.iter().enumerate() {
+            if signers[..i].contains(signer) {
+                return err!(CustomError::InvalidSignerSet);
+            }
+        }
+        Ok(())
+    }
+
+    // Counts the distinct members of the signer set among the signing remaining accounts
+    pub fn check_retrieval_signers(&self, remaining_accounts: &[AccountInfo]) -> Result<()> {
+        if self.threshold == 0 {
+            return Ok(());
+        }
+        let mut approvals: Vec<Pubkey> = Vec::new();
+        for account in remaining_accounts.iter() {
+            if account.is_signer && self.signers.contains(account.key) && !approvals.contains(account.key) {
+                approvals.push(*account.key);
+            }
+        }
+        if approvals.len() < self.threshold as usize {
+            return err!(CustomError::NotEnoughSignatures);
+        }
+        Ok(())
+    }
 }
 
 impl TokenMetadata {
@@ -349,7 +4132,174 @@ impl TokenMetadata {
     + 8     // escrow_number: u64
     + 32    // escrowed_token_mint: Pubkey
     + 8     // escrowed_amount: u64
-    + 8;    // bump: u64
+    + 8     // bump: u64
+    + 32    // recipient: Pubkey
+    + 32;   // collection: Pubkey
+}
+
+impl KeyCollection {
+    pub const LEN: usize =
+    8 // discriminator
+    + 32 // collection_mint: Pubkey
+    + 8 // minted: u64
+    + 1; // bump: u8
+}
+
+impl Config {
+    pub const LEN: usize =
+    8 // discriminator
+    + 32 // admin: Pubkey
+    + 1 // paused: bool
+    + 1 // allow_retrieve_while_paused: bool
+    + 1; // bump: u8
+
+    // Retrievals of already issued keys may stay open during a pause, if the admin allows it
+    pub fn retrieve_allowed(&self) -> bool {
+        !self.paused || self.allow_retrieve_while_paused
+    }
+}
+
+impl MintPolicy {
+    pub const LEN: usize =
+    8 // discriminator
+    + 32 // token_mint: Pubkey
+    + 1 // enabled: bool
+    + 8 // min_deposit: u64
+    + 8 // max_per_escrow: u64
+    + 8 // tvl_cap: u64
+    + 8 // total_deposited: u64
+    + 1 // bump: u8
+    + 1; // rewards_enabled: bool
+
+    // `deposit` is the amount being added now, `escrow_amount` is the resulting size of the escrow
+    pub fn record_deposit(&mut self, deposit: u64, escrow_amount: u64) -> Result<()> {
+        if !self.enabled {
+            return err!(CustomError::MintNotAllowed);
+        }
+        if deposit < self.min_deposit {
+            return err!(CustomError::DepositTooSmall);
+        }
+        if escrow_amount > self.max_per_escrow {
+            return err!(CustomError::DepositTooLarge);
+        }
+        let total_deposited = self.total_deposited.checked_add(deposit).ok_or(CustomError::MathOverflow)?;
+        if total_deposited > self.tvl_cap {
+            return err!(CustomError::TvlCapExceeded);
+        }
+        self.total_deposited = total_deposited;
+        Ok(())
+    }
+
+    pub fn record_withdrawal(&mut self, amount: u64) {
+        // Escrows created before the policy existed were never counted
+        self.total_deposited = self.total_deposited.saturating_sub(amount);
+    }
+}
+
+impl RewardsPool {
+    pub const LEN: usize =
+    8 // discriminator
+    + 32 // token_mint: Pubkey
+    + 32 // reward_mint: Pubkey
+    + 32 // reward_vault: Pubkey
+    + 8 // reward_rate: u64
+    + 16 // acc_reward_per_token: u128
+    + 8 // last_update_ts: i64
+    + 1 // bump: u8
+    + 8; // total_staked: u64
+
+    // Nothing is emitted while nothing is staked
+    pub fn update(&mut self, now: i64) -> Result<()> {
+        if now <= self.last_update_ts {
+            return Ok(());
+        }
+        if self.total_staked > 0 {
+            let emitted = (now - self.last_update_ts) as u128 * self.reward_rate as u128;
+            let increase = emitted.checked_mul(REWARD_PRECISION).ok_or(CustomError::MathOverflow)? / self.total_staked as u128;
+            self.acc_reward_per_token = self.acc_reward_per_token.checked_add(increase).ok_or(CustomError::MathOverflow)?;
+        }
+        self.last_update_ts = now;
+        Ok(())
+    }
+
+    pub fn pending(&self, stake: u64, reward_per_token_paid: u128) -> u64 {
+        let owed = stake as u128 * self.acc_reward_per_token.saturating_sub(reward_per_token_paid) / REWARD_PRECISION;
+        owed.min(u64::MAX as u128) as u64
+    }
+
+    // Replaces a `stake` checkpointed at `reward_per_token_paid` by `new_stake`, returns the rewards accrued by the old stake
+    pub fn restake(&mut self, stake: u64, reward_per_token_paid: u128, new_stake: u64, now: i64) -> Result<u64> {
+        self.update(now)?;
+        let rewards = self.pending(stake, reward_per_token_paid);
+        self.total_staked = self.total_staked.checked_sub(stake).and_then(|total| total.checked_add(new_stake)).ok_or(CustomError::MathOverflow)?;
+        Ok(rewards)
+    }
+}
+
+impl RecurringDeposit {
+    pub const LEN: usize =
+    8 // discriminator
+    + 32 // escrow: Pubkey
+    + 32 // owner: Pubkey
+    + 32 // source_token_ata: Pubkey
+    + 8 // amount: u64
+    + 8 // interval: i64
+    + 8 // next_deposit_at: i64
+    + 8 // instalments_remaining: u64
+    + 1; // bump: u8
+}
+
+impl Listing {
+    pub const LEN: usize =
+    8 // discriminator
+    + 32 // seller: Pubkey
+    + 32 // escrow: Pubkey
+    + 32 // nft_mint: Pubkey
+    + 1 + 32 // price_mint: Option<Pubkey>
+    + 8 // price: u64
+    + 1; // bump: u8
+}
+
+impl Auction {
+    pub const LEN: usize =
+    8 // discriminator
+    + 32 // seller: Pubkey
+    + 32 // escrow: Pubkey
+    + 32 // nft_mint: Pubkey
+    + 1 + 32 // bid_mint: Option<Pubkey>
+    + 8 // reserve_price: u64
+    + 8 // end_ts: i64
+    + 1 + 32 // highest_bidder: Option<Pubkey>
+    + 8 // highest_bid: u64
+    + 1; // bump: u8
+}
+
+impl LoanOffer {
+    pub const LEN: usize =
+    8 // discriminator
+    + 32 // lender: Pubkey
+    + 8 // offer_id: u64
+    + 1 + 32 // loan_mint: Option<Pubkey>
+    + 8 // principal: u64
+    + 8 // repayment: u64
+    + 8 // duration: i64
+    + 32 // collateral_token_mint: Pubkey
+    + 8 // min_escrowed_amount: u64
+    + 1; // bump: u8
+}
+
+impl Loan {
+    pub const LEN: usize =
+    8 // discriminator
+    + 32 // borrower: Pubkey
+    + 32 // lender: Pubkey
+    + 32 // escrow: Pubkey
+    + 32 // nft_mint: Pubkey
+    + 1 + 32 // loan_mint: Option<Pubkey>
+    + 8 // principal: u64
+    + 8 // repayment: u64
+    + 8 // due_ts: i64
+    + 1; // bump: u8
 }
 
 impl UserEscrowCounter {
@@ -361,10 +4311,842 @@ impl UserEscrowCounter {
     + 1; // bump: u8
 }
 
+// Events
+
+#[event]
+pub struct ProgramPaused {
+    pub admin: Pubkey,
+    pub allow_retrieve_while_paused: bool,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ProgramUnpaused {
+    pub admin: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct EscrowExpired {
+    pub escrow: Pubkey,
+    pub authority: Pubkey,
+    pub nft_mint: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct Heartbeat {
+    pub escrow: Pubkey,
+    pub last_heartbeat: i64,
+    pub claimable_at: i64,
+}
+
+#[event]
+pub struct InheritanceClaimed {
+    pub escrow: Pubkey,
+    pub authority: Pubkey,
+    pub beneficiary: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct SecretEscrowClaimed {
+    pub escrow: Pubkey,
+    pub claimant: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct SecretEscrowReclaimed {
+    pub escrow: Pubkey,
+    pub authority: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct VoucherRedeemed {
+    pub escrow: Pubkey,
+    pub recipient: Pubkey,
+    pub amount: u64,
+    pub nonce: u64,
+    pub remaining: u64,
+}
+
+#[event]
+pub struct StreamStarted {
+    pub escrow: Pubkey,
+    pub sender: Pubkey,
+    pub recipient: Pubkey,
+    pub start_ts: i64,
+    pub end_ts: i64,
+    pub rate_per_second: u64,
+}
+
+#[event]
+pub struct StreamWithdrawn {
+    pub escrow: Pubkey,
+    pub recipient: Pubkey,
+    pub amount: u64,
+    pub remaining: u64,
+}
+
+#[event]
+pub struct StreamCancelled {
+    pub escrow: Pubkey,
+    pub recipient_amount: u64,
+    pub sender_amount: u64,
+}
+
+#[event]
+pub struct RecurringDepositRegistered {
+    pub escrow: Pubkey,
+    pub amount: u64,
+    pub interval: i64,
+    pub instalments: u64,
+}
+
+#[event]
+pub struct RecurringDepositPulled {
+    pub escrow: Pubkey,
+    pub amount: u64,
+    pub token_amount: u64,
+    pub instalments_remaining: u64,
+}
+
+#[event]
+pub struct EscrowSplit {
+    pub escrow: Pubkey,
+    pub split_escrow: Pubkey,
+    pub holder: Pubkey,
+    pub remaining_amount: u64,
+    pub split_amount: u64,
+}
+
+#[event]
+pub struct EscrowsMerged {
+    pub escrow: Pubkey,
+    pub source_escrow: Pubkey,
+    pub holder: Pubkey,
+    pub token_amount: u64,
+}
+
+#[event]
+pub struct KeyRotated {
+    pub escrow: Pubkey,
+    pub holder: Pubkey,
+    pub old_nft_mint: Pubkey,
+    pub new_nft_mint: Pubkey,
+}
+
+#[event]
+pub struct KeyListed {
+    pub listing: Pubkey,
+    pub escrow: Pubkey,
+    pub seller: Pubkey,
+    pub price_mint: Option<Pubkey>,
+    pub price: u64,
+}
+
+#[event]
+pub struct ListingPriceUpdated {
+    pub listing: Pubkey,
+    pub price: u64,
+}
+
+#[event]
+pub struct ListingCancelled {
+    pub listing: Pubkey,
+    pub seller: Pubkey,
+}
+
+#[event]
+pub struct ListingSold {
+    pub listing: Pubkey,
+    pub escrow: Pubkey,
+    pub seller: Pubkey,
+    pub buyer: Pubkey,
+    pub price_mint: Option<Pubkey>,
+    pub price: u64,
+}
+
+#[event]
+pub struct AuctionCreated {
+    pub auction: Pubkey,
+    pub escrow: Pubkey,
+    pub seller: Pubkey,
+    pub bid_mint: Option<Pubkey>,
+    pub reserve_price: u64,
+    pub end_ts: i64,
+}
+
+#[event]
+pub struct BidPlaced {
+    pub auction: Pubkey,
+    pub bidder: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct AuctionSettled {
+    pub auction: Pubkey,
+    pub escrow: Pubkey,
+    pub seller: Pubkey,
+    pub winner: Option<Pubkey>,
+    pub amount: u64,
+}
+
+#[event]
+pub struct LoanOfferCreated {
+    pub offer: Pubkey,
+    pub lender: Pubkey,
+    pub loan_mint: Option<Pubkey>,
+    pub principal: u64,
+    pub repayment: u64,
+    pub duration: i64,
+}
+
+#[event]
+pub struct LoanOfferCancelled {
+    pub offer: Pubkey,
+    pub lender: Pubkey,
+}
+
+#[event]
+pub struct LoanTaken {
+    pub loan: Pubkey,
+    pub offer: Pubkey,
+    pub escrow: Pubkey,
+    pub borrower: Pubkey,
+    pub lender: Pubkey,
+    pub principal: u64,
+    pub repayment: u64,
+    pub due_ts: i64,
+}
+
+#[event]
+pub struct LoanRepaid {
+    pub loan: Pubkey,
+    pub borrower: Pubkey,
+    pub lender: Pubkey,
+    pub repayment: u64,
+}
+
+#[event]
+pub struct CollateralClaimed {
+    pub loan: Pubkey,
+    pub escrow: Pubkey,
+    pub borrower: Pubkey,
+    pub lender: Pubkey,
+}
+
+#[event]
+pub struct RewardsPoolCreated {
+    pub rewards_pool: Pubkey,
+    pub token_mint: Pubkey,
+    pub reward_mint: Pubkey,
+    pub reward_rate: u64,
+}
+
+#[event]
+pub struct RewardsPoolFunded {
+    pub rewards_pool: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct RewardRateUpdated {
+    pub rewards_pool: Pubkey,
+    pub reward_rate: u64,
+}
+
+#[event]
+pub struct RewardsClaimed {
+    pub escrow: Pubkey,
+    pub holder: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct ReleaseApproved {
+    pub escrow: Pubkey,
+    pub arbiter: Pubkey,
+}
+
+#[event]
+pub struct EscrowRefunded {
+    pub escrow: Pubkey,
+    pub arbiter: Pubkey,
+    pub authority: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct MilestonesCreated {
+    pub escrow: Pubkey,
+    pub payer: Pubkey,
+    pub payee: Pubkey,
+    pub count: u8,
+    pub total_amount: u64,
+}
+
+#[event]
+pub struct MilestoneSubmitted {
+    pub escrow: Pubkey,
+    pub index: u8,
+}
+
+#[event]
+pub struct MilestoneReleased {
+    pub escrow: Pubkey,
+    pub index: u8,
+    pub amount: u64,
+}
+
+#[event]
+pub struct MilestoneDisputed {
+    pub escrow: Pubkey,
+    pub index: u8,
+    pub opened_by: Pubkey,
+}
+
+#[event]
+pub struct MilestoneDisputeResolved {
+    pub escrow: Pubkey,
+    pub index: u8,
+    pub payee_amount: u64,
+    pub payer_amount: u64,
+}
+
+#[event]
+pub struct VestedClaimed {
+    pub escrow: Pubkey,
+    pub holder: Pubkey,
+    pub amount: u64,
+    pub remaining: u64,
+}
+
 #[error_code]
 pub enum CustomError {
     #[msg("Error: Provided user_token does not meet the constraints.")]
     InvalidUserToken,
+    #[msg("Error: The escrow program is paused.")]
+    ProgramPaused,
+    #[msg("Error: The escrow program is already paused.")]
+    AlreadyPaused,
+    #[msg("Error: The escrow program is not paused.")]
+    NotPaused,
+    #[msg("Error: The signer is not allowed to perform this action.")]
+    Unauthorized,
+    #[msg("Error: Minimum deposit cannot be greater than the maximum per escrow.")]
+    InvalidMintPolicy,
+    #[msg("Error: The token mint is not allowed in the deposit box.")]
+    MintNotAllowed,
+    #[msg("Error: The deposit is below the minimum for this mint.")]
+    DepositTooSmall,
+    #[msg("Error: The deposit exceeds the maximum per escrow for this mint.")]
+    DepositTooLarge,
+    #[msg("Error: The deposit would exceed the total value locked cap for this mint.")]
+    TvlCapExceeded,
+    #[msg("Error: The mint policy account is required for SPL-token escrows.")]
+    MissingMintPolicy,
+    #[msg("Error: Arithmetic overflow.")]
+    MathOverflow,
+    #[msg("Error: Provided escrow token account does not meet the constraints.")]
+    InvalidEscrowToken,
+    #[msg("Error: The escrow type has already been set.")]
+    EscrowKindAlreadySet,
+    #[msg("Error: The vesting schedule must satisfy start <= cliff <= end and start < end.")]
+    InvalidVestingSchedule,
+    #[msg("Error: The escrow is not a vesting escrow.")]
+    NotVestingEscrow,
+    #[msg("Error: The vesting schedule has not finished yet, use claim_vested.")]
+    VestingNotFinished,
+    #[msg("Error: There is nothing to claim yet.")]
+    NothingToClaim,
+    #[msg("Error: The signer does not hold the escrow's NFT.")]
+    InvalidNftHolder,
+    #[msg("Error: The arbiter has not approved the release yet.")]
+    ReleaseNotApproved,
+    #[msg("Error: The signer set must have at most 5 distinct members and 1 <= threshold <= members.")]
+    InvalidSignerSet,
+    #[msg("Error: Not enough members of the signer set have signed the retrieval.")]
+    NotEnoughSignatures,
+    #[msg("Error: The expiry must be in the future.")]
+    InvalidExpiry,
+    #[msg("Error: The escrow has expired, its assets can only be returned to the depositor.")]
+    EscrowExpired,
+    #[msg("Error: The escrow has not expired yet.")]
+    EscrowNotExpired,
+    #[msg("Error: The heartbeat interval must be positive.")]
+    InvalidHeartbeatInterval,
+    #[msg("Error: The escrow is not a dead man's switch escrow.")]
+    NotDeadManSwitchEscrow,
+    #[msg("Error: The depositor has not missed a heartbeat yet.")]
+    HeartbeatNotMissed,
+    #[msg("Error: The claim is not signed with the escrow's secret.")]
+    InvalidSecret,
+    #[msg("Error: The escrow is not a hash-locked escrow.")]
+    NotHashLockEscrow,
+    #[msg("Error: The escrow cannot be reclaimed before its timeout.")]
+    ReclaimTooEarly,
+    #[msg("Error: Vouchers can only be redeemed from standard escrows.")]
+    VoucherNotSupported,
+    #[msg("Error: The voucher has expired.")]
+    VoucherExpired,
+    #[msg("Error: The voucher nonce has already been used.")]
+    VoucherAlreadyUsed,
+    #[msg("Error: The voucher amount must be positive and at most the escrowed amount.")]
+    InvalidVoucherAmount,
+    #[msg("Error: The voucher is not signed by the depositor in a preceding Ed25519 instruction.")]
+    InvalidVoucherSignature,
+    #[msg("Error: The stream must start before it ends.")]
+    InvalidStream,
+    #[msg("Error: The escrow is not a stream escrow.")]
+    NotStreamEscrow,
+    #[msg("Error: Recurring deposits need a positive amount and number of instalments, and an interval of up to a year.")]
+    InvalidRecurringPlan,
+    #[msg("Error: Recurring deposits are only supported for standard SPL-token escrows.")]
+    RecurringNotSupported,
+    #[msg("Error: All instalments of the recurring plan have been deposited.")]
+    RecurringPlanFinished,
+    #[msg("Error: The next instalment is not due yet.")]
+    InstalmentNotDue,
+    #[msg("Error: Only standard escrows without an arbiter or expiry can be split.")]
+    SplitNotSupported,
+    #[msg("Error: The split amount must be positive and less than the escrowed amount.")]
+    InvalidSplitAmount,
+    #[msg("Error: Only standard escrows without an arbiter or expiry and with the same retrieval signers and soulbound flag can be merged.")]
+    MergeNotSupported,
+    #[msg("Error: Only escrows of the same asset can be merged.")]
+    MergeAssetMismatch,
+    #[msg("Error: The escrow can no longer be changed.")]
+    EscrowLocked,
+    #[msg("Error: No NFT can be minted for this type of escrow.")]
+    NoKeyForEscrowKind,
+    #[msg("Error: Milestone amounts must be non-empty, at most 8 and add up to the escrowed amount.")]
+    InvalidMilestones,
+    #[msg("Error: The escrow is not a milestone escrow.")]
+    NotMilestoneEscrow,
+    #[msg("Error: The milestone is not in a state that allows this action.")]
+    InvalidMilestoneStatus,
+    #[msg("Error: The escrow has no arbiter to resolve disputes.")]
+    NoArbiter,
+    #[msg("Error: The payee's share cannot exceed the milestone amount.")]
+    InvalidSplit,
+    #[msg("Error: Soulbound NFTs cannot be transferred.")]
+    KeySoulbound,
+    #[msg("Error: The listing price has changed.")]
+    ListingPriceChanged,
+    #[msg("Error: Provided payment token accounts do not match the listing.")]
+    InvalidPaymentToken,
+    #[msg("Error: The auction must end in the future.")]
+    InvalidAuctionEnd,
+    #[msg("Error: The auction has already ended.")]
+    AuctionEnded,
+    #[msg("Error: The auction has not ended yet.")]
+    AuctionNotEnded,
+    #[msg("Error: The bid must reach the reserve price and exceed the highest bid.")]
+    BidTooLow,
+    #[msg("Error: Provided accounts do not match the auction's seller or highest bidder.")]
+    InvalidAuctionParty,
+    #[msg("Error: The loan must be positive, repay at least the principal and have a positive duration.")]
+    InvalidLoanTerms,
+    #[msg("Error: The NFT does not meet the collateral requirements of the loan offer.")]
+    CollateralNotAccepted,
+    #[msg("Error: The loan is not overdue yet.")]
+    LoanNotDue,
+    #[msg("Error: The mint has a rewards pool, its accounts must be provided.")]
+    MissingRewardsPool,
+    #[msg("Error: The rewards pool does not hold enough reward tokens, please try again after it is funded.")]
+    RewardsPoolUnderfunded,
+    #[msg("Error: The escrows must belong to the user and be passed in ascending order of their escrow number.")]
+    InvalidEscrowList,
+    #[msg("Error: The NFT metadata account must be provided once the NFT has been minted.")]
+    MissingMetadata,
+    #[msg("Error: The key mint must have a supply of exactly one token.")]
+    InvalidKeySupply,
+    #[msg("Error: This instruction is not supported for this type of escrow.")]
+    UnsupportedEscrowKind,
+    #[msg("Error: The account is not an escrow created by an earlier version of the program.")]
+    NotLegacyEscrow,
+    #[msg("Error: The escrow number does not match the escrow account.")]
+    InvalidEscrowNumber,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vested_amount_is_zero_before_the_cliff() {
+        assert_eq!(vested_amount(1_000, 100, 200, 1_100, 100), 0);
+        assert_eq!(vested_amount(1_000, 100, 200, 1_100, 199), 0);
+    }
+
+    #[test]
+    fn vested_amount_catches_up_at_the_cliff() {
+        // Everything accrued since `start_ts` unlocks at once
+        assert_eq!(vested_amount(1_000, 100, 200, 1_100, 200), 100);
+    }
+
+    #[test]
+    fn vested_amount_is_linear_between_cliff_and_end() {
+        assert_eq!(vested_amount(1_000, 100, 100, 1_100, 600), 500);
+        assert_eq!(vested_amount(1_000, 100, 100, 1_100, 1_099), 999);
+    }
+
+    #[test]
+    fn vested_amount_is_complete_from_the_end() {
+        assert_eq!(vested_amount(1_000, 100, 200, 1_100, 1_100), 1_000);
+        assert_eq!(vested_amount(1_000, 100, 200, 1_100, i64::MAX), 1_000);
+    }
+
+    #[test]
+    fn vested_amount_rounds_down() {
+        // 7 tokens over 3 seconds: 2.33 and 4.66 vested after 1 and 2 seconds
+        assert_eq!(vested_amount(7, 0, 0, 3, 1), 2);
+        assert_eq!(vested_amount(7, 0, 0, 3, 2), 4);
+        assert_eq!(vested_amount(7, 0, 0, 3, 3), 7);
+    }
+
+    #[test]
+    fn vested_amount_does_not_overflow() {
+        assert_eq!(vested_amount(u64::MAX, 0, 0, 2, 1), u64::MAX / 2);
+    }
+
+    #[test]
+    fn streamed_amount_is_zero_until_the_start() {
+        assert_eq!(streamed_amount(1_000, 100, 1_100, i64::MIN), 0);
+        assert_eq!(streamed_amount(1_000, 100, 1_100, 100), 0);
+    }
+
+    #[test]
+    fn streamed_amount_is_linear_until_the_end() {
+        assert_eq!(streamed_amount(1_000, 100, 1_100, 101), 1);
+        assert_eq!(streamed_amount(1_000, 100, 1_100, 600), 500);
+        assert_eq!(streamed_amount(1_000, 100, 1_100, 1_100), 1_000);
+        assert_eq!(streamed_amount(1_000, 100, 1_100, i64::MAX), 1_000);
+    }
+
+    #[test]
+    fn streamed_amount_accrues_amounts_smaller_than_the_duration() {
+        // The per-second rate rounds down to 0 here, the stream must still flow
+        assert_eq!(streamed_amount(5, 0, 10, 4), 2);
+        assert_eq!(streamed_amount(5, 0, 10, 5), 2);
+        assert_eq!(streamed_amount(5, 0, 10, 6), 3);
+    }
+
+    #[test]
+    fn streamed_amount_does_not_overflow() {
+        assert_eq!(streamed_amount(u64::MAX, 0, 4, 3), u64::MAX / 4 * 3 + 2);
+    }
+
+    fn rewards_pool(reward_rate: u64) -> RewardsPool {
+        RewardsPool {
+            token_mint: Pubkey::default(),
+            reward_mint: Pubkey::default(),
+            reward_vault: Pubkey::default(),
+            reward_rate,
+            acc_reward_per_token: 0,
+            last_update_ts: 0,
+            bump: 0,
+            total_staked: 0,
+        }
+    }
+
+    #[test]
+    fn rewards_pool_emits_nothing_while_nothing_is_staked() {
+        let mut pool = rewards_pool(10);
+        pool.update(100).unwrap();
+        assert_eq!(pool.acc_reward_per_token, 0);
+        assert_eq!(pool.last_update_ts, 100);
+        // Going back in time changes nothing
+        pool.update(50).unwrap();
+        assert_eq!(pool.last_update_ts, 100);
+    }
+
+    #[test]
+    fn rewards_pool_shares_emission_by_stake() {
+        let mut pool = rewards_pool(8);
+        assert_eq!(pool.restake(0, 0, 100, 0).unwrap(), 0);
+        assert_eq!(pool.restake(0, 0, 300, 0).unwrap(), 0);
+        assert_eq!(pool.total_staked, 400);
+        pool.update(10).unwrap();
+        assert_eq!(pool.pending(100, 0), 20);
+        assert_eq!(pool.pending(300, 0), 60);
+    }
+
+    #[test]
+    fn rewards_pool_pending_rounds_down() {
+        let mut pool = rewards_pool(1);
+        pool.restake(0, 0, 3, 0).unwrap();
+        pool.update(1).unwrap();
+        // 1 token per 3 staked tokens: a single staked token has not earned a whole reward token yet
+        assert_eq!(pool.pending(1, 0), 0);
+        assert_eq!(pool.pending(3, 0), 0);
+        pool.update(3).unwrap();
+        assert_eq!(pool.pending(3, 0), 2);
+    }
+
+    #[test]
+    fn rewards_pool_settles_stakes_before_they_change() {
+        // Two escrows of 100 for 20 seconds at 10 tokens per second, one withdraws half after 10 seconds
+        let mut pool = rewards_pool(10);
+        pool.restake(0, 0, 100, 0).unwrap();
+        pool.restake(0, 0, 100, 0).unwrap();
+        let first = pool.restake(100, 0, 50, 10).unwrap();
+        let first_paid = pool.acc_reward_per_token;
+        assert_eq!(first, 50);
+        assert_eq!(pool.total_staked, 150);
+        let first = first + pool.restake(50, first_paid, 0, 20).unwrap();
+        let second = pool.restake(100, 0, 0, 20).unwrap();
+        assert_eq!((first, second), (83, 116));
+        assert_eq!(pool.total_staked, 0);
+    }
+
+    #[test]
+    fn rewards_pool_never_pays_out_more_than_emitted() {
+        // A top-up must not earn the rewards accrued before it
+        let mut pool = rewards_pool(10);
+        pool.restake(0, 0, 100, 0).unwrap();
+        pool.restake(0, 0, 100, 0).unwrap();
+        let mut topped_up = pool.restake(100, 0, 1_000, 10).unwrap();
+        let topped_up_paid = pool.acc_reward_per_token;
+        topped_up += pool.restake(1_000, topped_up_paid, 0, 20).unwrap();
+        let other = pool.restake(100, 0, 0, 20).unwrap();
+        assert!(topped_up + other <= 200);
+        assert_eq!((topped_up, other), (140, 59));
+    }
+
+    #[test]
+    fn rewards_pool_rejects_unstaking_more_than_staked() {
+        let mut pool = rewards_pool(10);
+        pool.restake(0, 0, 100, 0).unwrap();
+        assert!(pool.restake(101, 0, 0, 10).is_err());
+    }
+
+    #[test]
+    fn legacy_escrow_len_matches_the_first_version() {
+        // Escrow accounts created by the first version of the program are 147 bytes
+        let legacy = LegacyEscrow {
+            authority: Pubkey::new_unique(),
+            bump: 255,
+            token_mint: Pubkey::new_unique(),
+            escrow_token_ata: Pubkey::new_unique(),
+            token_amount: u64::MAX,
+            nft_mint: Pubkey::new_unique(),
+            nft_acquired: true,
+            is_solana: false,
+        };
+        assert_eq!(LegacyEscrow::LEN, 147);
+        assert_eq!(8 + legacy.try_to_vec().unwrap().len(), LegacyEscrow::LEN);
+    }
+
+    fn mint_policy(min_deposit: u64, max_per_escrow: u64, tvl_cap: u64) -> MintPolicy {
+        MintPolicy {
+            token_mint: Pubkey::default(),
+            enabled: true,
+            min_deposit,
+            max_per_escrow,
+            tvl_cap,
+            total_deposited: 0,
+            bump: 0,
+            rewards_enabled: false,
+        }
+    }
+
+    #[test]
+    fn mint_policy_counts_deposits_up_to_the_caps() {
+        let mut policy = mint_policy(10, 100, 150);
+        policy.record_deposit(100, 100).unwrap();
+        policy.record_deposit(50, 50).unwrap();
+        assert_eq!(policy.total_deposited, 150);
+    }
+
+    #[test]
+    fn mint_policy_rejects_deposits_outside_the_limits() {
+        let mut policy = mint_policy(10, 100, 1_000);
+        assert_eq!(policy.record_deposit(9, 9).unwrap_err(), CustomError::DepositTooSmall.into());
+        // A top-up is checked against the resulting size of the escrow
+        assert_eq!(policy.record_deposit(10, 101).unwrap_err(), CustomError::DepositTooLarge.into());
+        policy.enabled = false;
+        assert_eq!(policy.record_deposit(10, 10).unwrap_err(), CustomError::MintNotAllowed.into());
+        assert_eq!(policy.total_deposited, 0);
+    }
+
+    #[test]
+    fn mint_policy_rejects_deposits_over_the_tvl_cap() {
+        let mut policy = mint_policy(0, u64::MAX, 150);
+        policy.record_deposit(100, 100).unwrap();
+        assert_eq!(policy.record_deposit(51, 51).unwrap_err(), CustomError::TvlCapExceeded.into());
+        assert_eq!(policy.total_deposited, 100);
+    }
+
+    #[test]
+    fn mint_policy_rejects_deposits_overflowing_the_total() {
+        let mut policy = mint_policy(0, u64::MAX, u64::MAX);
+        policy.record_deposit(u64::MAX, u64::MAX).unwrap();
+        assert_eq!(policy.record_deposit(1, 1).unwrap_err(), CustomError::MathOverflow.into());
+        assert_eq!(policy.total_deposited, u64::MAX);
+    }
+
+    #[test]
+    fn mint_policy_withdrawals_of_uncounted_escrows_do_not_underflow() {
+        let mut policy = mint_policy(0, 100, 100);
+        policy.record_deposit(40, 40).unwrap();
+        policy.record_withdrawal(60);
+        assert_eq!(policy.total_deposited, 0);
+    }
+
+    fn escrow_with_signers(signers: Vec<Pubkey>, threshold: u8) -> Escrow {
+        Escrow {
+            authority: Pubkey::default(),
+            bump: 0,
+            token_mint: Pubkey::default(),
+            escrow_token_ata: Pubkey::default(),
+            token_amount: 0,
+            nft_mint: Pubkey::default(),
+            nft_acquired: true,
+            is_solana: false,
+            kind: EscrowKind::Standard,
+            escrow_number: 0,
+            arbiter: None,
+            release_approved: false,
+            signers,
+            threshold,
+            expires_at: None,
+            voucher_nonce: 0,
+            soulbound: false,
+            reward_per_token_paid: 0,
+            reward_stake: 0,
+            rewards_owed: 0,
+        }
+    }
+
+    // Runs `check_retrieval_signers` with the given (key, is_signer) pairs as the remaining accounts
+    fn check_signers(escrow: &Escrow, accounts: &[(Pubkey, bool)]) -> Result<()> {
+        let owner = Pubkey::default();
+        let mut lamports = vec![0u64; accounts.len()];
+        let mut data = vec![[0u8; 0]; accounts.len()];
+        let infos: Vec<AccountInfo> = accounts
+            .iter()
+            .zip(lamports.iter_mut())
+            .zip(data.iter_mut())
+            .map(|(((key, is_signer), lamports), data)| AccountInfo::new(key, *is_signer, false, lamports, data, &owner, false, 0))
+            .collect();
+        escrow.check_retrieval_signers(&infos)
+    }
+
+    #[test]
+    fn signer_set_needs_a_reachable_threshold() {
+        let signers = [Pubkey::new_unique(), Pubkey::new_unique()];
+        assert!(Escrow::check_signer_set(&signers, 1).is_ok());
+        assert!(Escrow::check_signer_set(&signers, 2).is_ok());
+        assert_eq!(Escrow::check_signer_set(&signers, 0).unwrap_err(), CustomError::InvalidSignerSet.into());
+        assert_eq!(Escrow::check_signer_set(&signers, 3).unwrap_err(), CustomError::InvalidSignerSet.into());
+        let too_many: Vec<Pubkey> = (0..=MAX_SIGNERS).map(|_| Pubkey::new_unique()).collect();
+        assert_eq!(Escrow::check_signer_set(&too_many, 1).unwrap_err(), CustomError::InvalidSignerSet.into());
+    }
+
+    #[test]
+    fn signer_set_rejects_duplicate_signers() {
+        let (a, b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        // Otherwise a single key could meet a threshold of 2
+        assert_eq!(Escrow::check_signer_set(&[a, b, a], 2).unwrap_err(), CustomError::InvalidSignerSet.into());
+    }
+
+    #[test]
+    fn retrieval_signers_are_not_needed_without_a_threshold() {
+        let escrow = escrow_with_signers(Vec::new(), 0);
+        assert!(check_signers(&escrow, &[]).is_ok());
+    }
+
+    #[test]
+    fn retrieval_signers_reach_the_threshold() {
+        let (a, b, c) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let escrow = escrow_with_signers(vec![a, b, c], 2);
+        assert!(check_signers(&escrow, &[(c, true), (a, true)]).is_ok());
+        assert_eq!(check_signers(&escrow, &[(a, true)]).unwrap_err(), CustomError::NotEnoughSignatures.into());
+    }
+
+    #[test]
+    fn retrieval_signers_count_duplicates_once() {
+        let (a, b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let escrow = escrow_with_signers(vec![a, b], 2);
+        assert_eq!(check_signers(&escrow, &[(a, true), (a, true)]).unwrap_err(), CustomError::NotEnoughSignatures.into());
+    }
+
+    #[test]
+    fn retrieval_signers_ignore_non_signers_and_outsiders() {
+        let (a, b, outsider) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let escrow = escrow_with_signers(vec![a, b], 2);
+        // `b` is passed without signing, the outsider signs but is not in the set
+        assert_eq!(check_signers(&escrow, &[(a, true), (b, false), (outsider, true)]).unwrap_err(), CustomError::NotEnoughSignatures.into());
+    }
+
+    // Lays out an Ed25519 program instruction the way the web3.js helper does:
+    // header, public key, signature, then the message
+    fn ed25519_data(signer: &Pubkey, message: &[u8]) -> Vec<u8> {
+        let (public_key_offset, signature_offset, message_offset) = (16u16, 48u16, 112u16);
+        let mut data = vec![1, 0];
+        for value in [signature_offset, u16::MAX, public_key_offset, u16::MAX, message_offset, message.len() as u16, u16::MAX] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(signer.as_ref());
+        data.extend_from_slice(&[0; 64]);
+        data.extend_from_slice(message);
+        data
+    }
+
+    fn set_u16(data: &mut [u8], at: usize, value: u16) {
+        data[at..at + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn ed25519_data_accepts_matching_signer_and_message() {
+        let signer = Pubkey::new_unique();
+        assert!(check_ed25519_data(&ed25519_data(&signer, b"voucher"), &signer, b"voucher").is_ok());
+    }
+
+    #[test]
+    fn ed25519_data_rejects_other_signer_or_message() {
+        let signer = Pubkey::new_unique();
+        let data = ed25519_data(&signer, b"voucher");
+        assert!(check_ed25519_data(&data, &Pubkey::new_unique(), b"voucher").is_err());
+        assert!(check_ed25519_data(&data, &signer, b"vouchers").is_err());
+    }
+
+    #[test]
+    fn ed25519_data_rejects_malformed_headers() {
+        let signer = Pubkey::new_unique();
+        let data = ed25519_data(&signer, b"voucher");
+        assert!(check_ed25519_data(&data[..15], &signer, b"voucher").is_err());
+        let mut two_signatures = data.clone();
+        two_signatures[0] = 2;
+        assert!(check_ed25519_data(&two_signatures, &signer, b"voucher").is_err());
+        // Data taken from another instruction of the transaction
+        for at in [4, 8, 14] {
+            let mut other_instruction = data.clone();
+            set_u16(&mut other_instruction, at, 0);
+            assert!(check_ed25519_data(&other_instruction, &signer, b"voucher").is_err());
+        }
+    }
+
+    #[test]
+    fn ed25519_data_rejects_out_of_bounds_offsets() {
+        let signer = Pubkey::new_unique();
+        let data = ed25519_data(&signer, b"voucher");
+        let mut public_key_past_end = data.clone();
+        set_u16(&mut public_key_past_end, 6, data.len() as u16 - 31);
+        assert!(check_ed25519_data(&public_key_past_end, &signer, b"voucher").is_err());
+        let mut message_past_end = data.clone();
+        set_u16(&mut message_past_end, 12, u16::MAX);
+        assert!(check_ed25519_data(&message_past_end, &signer, b"voucher").is_err());
+        let mut offsets_at_max = data;
+        set_u16(&mut offsets_at_max, 6, u16::MAX);
+        set_u16(&mut offsets_at_max, 10, u16::MAX);
+        assert!(check_ed25519_data(&offsets_at_max, &signer, b"voucher").is_err());
+    }
 }
 
 /*
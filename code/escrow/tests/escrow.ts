@@ -2,88 +2,178 @@ import * as anchor from "@coral-xyz/anchor";
 import * as splToken from "@solana/spl-token";
 import { Program } from "@coral-xyz/anchor";
 import { EscrowHeadonAttempt } from "../target/types/escrow_headon_attempt";
-import { LAMPORTS_PER_SOL, SYSVAR_RENT_PUBKEY} from "@solana/web3.js";
+import { LAMPORTS_PER_SOL, PublicKey, SYSVAR_RENT_PUBKEY } from "@solana/web3.js";
 import NodeWallet from "@coral-xyz/anchor/dist/cjs/nodewallet";
-import { token } from "@coral-xyz/anchor/dist/cjs/utils";
+import { assert } from "chai";
 
+const BPF_LOADER_UPGRADEABLE_PROGRAM_ID = new PublicKey("BPFLoaderUpgradeab1e11111111111111111111111");
 
 describe("EscrowHeadOnAttempt", () => {
-  const provider =  anchor.AnchorProvider.env();
+  const provider = anchor.AnchorProvider.env();
   anchor.setProvider(provider);
   const program = anchor.workspace.EscrowHeadonAttempt as Program<EscrowHeadonAttempt>;
-  
-  const user =  anchor.web3.Keypair.generate();
+
+  const user = anchor.web3.Keypair.generate();
+  // Second signature required for the retrieval (1-of-1 retrieval signer set)
+  const cosigner = anchor.web3.Keypair.generate();
   const payer = (provider.wallet as NodeWallet).payer;
-  const escrowTAKeypair = anchor.web3.Keypair.generate();
-  let token_mint;
-  let user_token_account;
-  let escrow: anchor.web3.PublicKey;
-  
-  before(async() => {
-    await provider.connection.requestAirdrop(user.publicKey, 1 * LAMPORTS_PER_SOL);
-    // Derive escrow address
-    [escrow] = await anchor.web3.PublicKey.findProgramAddress([
-      anchor.utils.bytes.utf8.encode("escrow"),
-      user.publicKey.toBuffer()
-    ], 
-    program.programId)
-
-    token_mint = await splToken.createMint(
-      provider.connection,
-      payer,
-      provider.wallet.publicKey,
-      provider.wallet.publicKey,
-      6
+  const nftMint = anchor.web3.Keypair.generate();
+  const escrowTokenAta = anchor.web3.Keypair.generate();
+  const tokenAmount = 40;
+  let tokenMint: PublicKey;
+  let userTokenAta: PublicKey;
+  let userNftAta: PublicKey;
+  let config: PublicKey;
+  let mintPolicy: PublicKey;
+  let counter: PublicKey;
+  let escrow: PublicKey;
+  let metadata: PublicKey;
+
+  const pda = (seeds: (Buffer | Uint8Array)[]) => PublicKey.findProgramAddressSync(seeds, program.programId)[0];
+
+  before(async () => {
+    await provider.connection.confirmTransaction(
+      await provider.connection.requestAirdrop(user.publicKey, 1 * LAMPORTS_PER_SOL)
     );
 
+    tokenMint = await splToken.createMint(provider.connection, payer, payer.publicKey, payer.publicKey, 6);
+    userTokenAta = await splToken.createAssociatedTokenAccount(provider.connection, payer, tokenMint, user.publicKey);
+    await splToken.mintTo(provider.connection, payer, tokenMint, userTokenAta, payer, 1_000);
+    userNftAta = await splToken.getAssociatedTokenAddress(nftMint.publicKey, user.publicKey);
 
-    user_token_account = await splToken.createAssociatedTokenAccount(
-      provider.connection,
-      payer,
-      token_mint,      // mint address
-      user.publicKey, // owner
-      );
+    config = pda([anchor.utils.bytes.utf8.encode("config")]);
+    mintPolicy = pda([anchor.utils.bytes.utf8.encode("mint_policy"), tokenMint.toBuffer()]);
+    counter = pda([anchor.utils.bytes.utf8.encode("counter"), user.publicKey.toBuffer()]);
+    // The user's first escrow (counter 0)
+    escrow = pda([anchor.utils.bytes.utf8.encode("escrow"), user.publicKey.toBuffer(), new anchor.BN(0).toArrayLike(Buffer, "le", 8)]);
+    metadata = pda([anchor.utils.bytes.utf8.encode("metadata"), nftMint.publicKey.toBuffer()]);
+  });
 
-    await splToken.mintTo(
-      provider.connection,
-      payer,
-      token_mint,
-      user_token_account,
-      payer,
-      10_000_000_000);
+  it("Initializes the config and allowlists the mint", async () => {
+    // The local validator deploys the program with the provider wallet as its upgrade authority
+    const [programData] = PublicKey.findProgramAddressSync([program.programId.toBuffer()], BPF_LOADER_UPGRADEABLE_PROGRAM_ID);
+    await program.methods.initConfig(false)
+      .accounts({
+        admin: payer.publicKey,
+        config: config,
+        program: program.programId,
+        programData: programData,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc();
 
-  })
+    await program.methods.registerMint(new anchor.BN(1), new anchor.BN(100), new anchor.BN(1_000))
+      .accounts({
+        admin: payer.publicKey,
+        config: config,
+        tokenMint: tokenMint,
+        mintPolicy: mintPolicy,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc();
+  });
+
+  it("Deposits tokens into an escrow", async () => {
+    await program.methods.initCounter()
+      .accounts({
+        user: user.publicKey,
+        userEscrowCounter: counter,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([user])
+      .rpc();
 
-  // idk
-  it("Initialize escrow", async () => {
-    const token_amount = new anchor.BN(40);
-    const tx = await program.methods.initialize(token_amount)
+    await program.methods.initializeTokenEscrow(new anchor.BN(tokenAmount))
       .accounts({
         user: user.publicKey,
-        tokenMint: token_mint,
-        userToken: user_token_account,
+        config: config,
+        tokenMint: tokenMint,
+        mintPolicy: mintPolicy,
+        userTokenAta: userTokenAta,
+        nftMint: nftMint.publicKey,
         escrow: escrow,
-        escrowedTokensTokenAccount: escrowTAKeypair.publicKey,
+        userEscrowCounter: counter,
+        escrowTokenAta: escrowTokenAta.publicKey,
         tokenProgram: splToken.TOKEN_PROGRAM_ID,
         rent: SYSVAR_RENT_PUBKEY,
-        systemProgram: anchor.web3.SystemProgram.programId
+        systemProgram: anchor.web3.SystemProgram.programId,
       })
-      .signers([user, escrowTAKeypair]) // I added the user Keypair as a signer
-      .rpc()
+      .signers([user, nftMint, escrowTokenAta])
+      .rpc();
+
+    const escrowAccount = await program.account.escrow.fetch(escrow);
+    assert.equal(escrowAccount.tokenAmount.toNumber(), tokenAmount);
+    assert.equal((await program.account.mintPolicy.fetch(mintPolicy)).totalDeposited.toNumber(), tokenAmount);
   });
 
-  // not there yet
-  it("Retrieve from Escrow", async () => { 
-    const tx = await program.methods.retrieve()
+  it("Requires a co-signer for the retrieval and mints the key", async () => {
+    await program.methods.setRetrievalSigners([cosigner.publicKey], 1)
       .accounts({
         user: user.publicKey,
+        config: config,
         escrow: escrow,
-        escrowedTokensTokenAccount: escrowTAKeypair.publicKey,
-        tokensTokenAccount: user_token_account,
-        tokenProgram: splToken.TOKEN_PROGRAM_ID
       })
-      .signers([user]) // I added the user Keypair as a signer
-      .rpc()
+      .signers([user])
+      .rpc();
+
+    await program.methods.getNft()
+      .accounts({
+        user: user.publicKey,
+        config: config,
+        collection: null,
+        nftMint: nftMint.publicKey,
+        metadataAccount: metadata,
+        escrow: escrow,
+        userEscrowCounter: counter,
+        userNftAta: userNftAta,
+        mintPolicy: mintPolicy,
+        rewardsPool: null,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        associatedTokenProgram: splToken.ASSOCIATED_TOKEN_PROGRAM_ID,
+      })
+      .signers([user])
+      .rpc();
+
+    assert.equal(Number((await splToken.getAccount(provider.connection, userNftAta)).amount), 1);
+  });
+
+  const retrieve = (cosigners: anchor.web3.Keypair[]) => program.methods.retrieve()
+    .accounts({
+      user: user.publicKey,
+      holder: user.publicKey,
+      config: config,
+      nftMint: nftMint.publicKey,
+      metadataAccount: metadata,
+      escrow: escrow,
+      userNftAta: userNftAta,
+      escrowTokenAta: escrowTokenAta.publicKey,
+      userTokenAta: userTokenAta,
+      mintPolicy: mintPolicy,
+      rewardsPool: null,
+      rewardVault: null,
+      holderRewardAta: null,
+      tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    })
+    .remainingAccounts(cosigners.map((cosigner) => ({ pubkey: cosigner.publicKey, isSigner: true, isWritable: false })))
+    .signers([user, ...cosigners])
+    .rpc();
+
+  it("Rejects a retrieval without the co-signer", async () => {
+    try {
+      await retrieve([]);
+      assert.fail("The retrieval should have failed");
+    } catch (error) {
+      assert.equal(error.error?.errorCode?.code, "NotEnoughSignatures");
+    }
   });
 
-});
\ No newline at end of file
+  it("Retrieves the tokens with the co-signer", async () => {
+    await retrieve([cosigner]);
+
+    assert.equal(Number((await splToken.getAccount(provider.connection, userTokenAta)).amount), 1_000);
+    assert.isNull(await program.account.escrow.fetchNullable(escrow));
+    assert.equal((await program.account.mintPolicy.fetch(mintPolicy)).totalDeposited.toNumber(), 0);
+  });
+});